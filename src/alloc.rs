@@ -1,18 +1,31 @@
-
+// Headless builds (see the `headless` feature) run on the host, where the system allocator already works,
+// so everything here is only for the real WASM-4 memory layout.
+#[cfg(not(feature = "headless"))]
 const TOTAL_MEM_SIZE: usize = 64 * 1024;
-const NON_HEAP_STATIC_ALLOC_SPACE: usize = 2 * 1024; // 10 KB
-const STACK_TOP_ADDR: usize = 14752;
 
+#[cfg(not(feature = "headless"))]
 use linked_list_allocator::LockedHeap;
 
+#[cfg(not(feature = "headless"))]
 #[global_allocator]
 static ALLOCATOR: LockedHeap = LockedHeap::empty();
 
+#[cfg(not(feature = "headless"))]
+extern "C" {
+    // Set by the linker to the first address after the stack and all static data (including the static ECS).
+    static __heap_base: u8;
+}
+
+#[cfg(not(feature = "headless"))]
 pub fn init_heap() {
-    let heap_start = STACK_TOP_ADDR + NON_HEAP_STATIC_ALLOC_SPACE;
+    // Start right after the static data instead of at a fixed offset, so a growing static ECS can never overlap the heap.
+    let heap_start = core::ptr::addr_of!(__heap_base) as usize;
     let heap_end = TOTAL_MEM_SIZE;
     let heap_size = heap_end - heap_start;
     unsafe {
         ALLOCATOR.lock().init(heap_start as *mut u8, heap_size);
     }
-}
\ No newline at end of file
+}
+
+#[cfg(feature = "headless")]
+pub fn init_heap() {}
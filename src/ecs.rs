@@ -1,20 +1,51 @@
 // Credit for this implementation outline to Kyren https://kyren.github.io/2018/09/14/rustconf-talk.html
 
+// Pick the handle sizes with cargo features: `index-u8` halves the index for games with at most 256 entities,
+// and `generation-u64` makes generations practically impossible to run out of.
+#[cfg(feature = "index-u8")]
+pub type IndexType = u8;
+#[cfg(not(feature = "index-u8"))]
 pub type IndexType = u16;
+#[cfg(feature = "generation-u64")]
+pub type GenerationType = u64;
+#[cfg(not(feature = "generation-u64"))]
 pub type GenerationType = u32;
+pub type WorldIdType = u8;
 
 /// Represent an index that always points to a small number in a vector, but also has a generation that allows it to expire. 
-/// You can change this struct's internal size types if these are too large.
+/// You can change this struct's internal size types if these are too large (see the `IndexType` and `GenerationType` features).
 #[derive(Eq, PartialEq, Clone, Copy)]
 pub struct GenerationalIndex {
     index: IndexType,
     generation: GenerationType,
+    world: WorldIdType,
+}
+
+impl GenerationalIndex {
+    /// Pack the whole handle into one number, e.g. for hashing or storing it somewhere outside the ECS.
+    /// The index is in the low 16 bits, the world id in the next 8, and the generation in the top 40
+    /// (so with the `generation-u64` feature, generations past 2^40 lose their top bits).
+    pub fn to_raw(&self) -> u64 {
+        (self.generation as u64) << 24 | (self.world as u64) << 16 | self.index as u64
+    }
+
+    /// Unpack a handle packed with `to_raw`. The handle is only meaningful in the allocator it came from
+    /// (or one restored from a save with `allocate_at`).
+    pub fn from_raw(raw: u64) -> GenerationalIndex {
+        GenerationalIndex {
+            index: raw as u16 as IndexType,
+            generation: (raw >> 24) as GenerationType,
+            world: (raw >> 16) as WorldIdType,
+        }
+    }
 }
 
 /// Represent available spots in the generational allocator. This stays public even though it's really for internal use, so that the allocation for these happens upfront explicitly (see demo usage).
 pub struct AllocatorEntry {
     is_live: bool,
     generation: GenerationType,
+    // The allocator's frame when this entry was last allocated.
+    allocated_at: u32,
 }
 
 impl AllocatorEntry {
@@ -22,10 +53,17 @@ impl AllocatorEntry {
         AllocatorEntry {
             is_live: false,
             generation: 0,
+            allocated_at: 0,
         }
     }
 }
 
+impl Default for AllocatorEntry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 /// Represent which indecies are currently in use by which generation, and handle allocation and deallocation of these indecies.
 /// This does NOT allocate the actual data stored in the entity component system, JUST the indecies.
 /// This is on purpose; it allows manual management of the component memory by the user.
@@ -33,6 +71,24 @@ pub struct GenerationalIndexAllocator {
     entries: Vec<AllocatorEntry>,
     free: Vec<IndexType>,
     generation_counter: GenerationType,
+    world_id: WorldIdType,
+    current_frame: u32,
+    free_strategy: FreeStrategy,
+    // Lifetime counts of successful allocations and deallocations, for spotting leaks.
+    total_allocated: u32,
+    total_deallocated: u32,
+}
+
+/// How `allocate` picks which free index to hand out next.
+/// Both are deterministic: the same sequence of allocations and deallocations always gives the same handles. But switching
+/// strategies changes which slots later entities get, so handles only match a recorded run that switched at the same points.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FreeStrategy {
+    /// Reuse the most recently freed index first (the default). Constant time.
+    Stack,
+    /// Always hand out the lowest free index, so e.g. a level always fills slots 0, 1, 2, ... no matter what was freed
+    /// before. This has to search the free list, so allocating takes time proportional to the number of free indices.
+    LowestIndexFirst,
 }
 
 impl GenerationalIndexAllocator {
@@ -41,6 +97,11 @@ impl GenerationalIndexAllocator {
             entries,
             free,
             generation_counter: 0,
+            world_id: 0,
+            current_frame: 0,
+            free_strategy: FreeStrategy::Stack,
+            total_allocated: 0,
+            total_deallocated: 0,
         }
     }
 }
@@ -50,14 +111,36 @@ pub struct AllocatorOutOfMemory(());
 pub enum DeallocationError {
     IndexOOB,
     GenerationMismatch,
-    AlreadyDeallocated
+    AlreadyDeallocated,
+    WorldMismatch
 }
 
 #[derive(Debug)]
 pub enum GenerationalIndexError {
     IndexOOB,
     GenerationMismatch,
-    NotLive
+    NotLive,
+    WorldMismatch,
+    // The slot's storage hasn't been allocated yet (see `LazyGenerationalIndexArray`).
+    NotSet,
+    // The value wasn't written, because it would give more entities this component than the soft cap allows
+    // (see `GenerationalIndexArray::set_capped`).
+    OverSoftCap,
+    // Two handles were needed at once (see `GenerationalIndexArray::get2_mut`), but they point at the same slot.
+    SameIndex,
+}
+
+#[derive(Debug)]
+pub enum AllocateAtError {
+    IndexOOB,
+    WorldMismatch,
+    AlreadyLive
+}
+
+#[derive(Debug)]
+pub enum SpawnError {
+    OutOfMemory,
+    ComponentSet(GenerationalIndexError)
 }
 
 impl GenerationalIndexAllocator {
@@ -66,14 +149,25 @@ impl GenerationalIndexAllocator {
     pub fn allocate(&mut self) -> Result<GenerationalIndex, AllocatorOutOfMemory> {
         // try to find a free spot.
 
-        match self.free.pop() {
+        let next_free = match self.free_strategy {
+            FreeStrategy::Stack => self.free.pop(),
+            FreeStrategy::LowestIndexFirst => {
+                let lowest = self.free.iter().enumerate().min_by_key(|(_, &index)| index).map(|(pos, _)| pos);
+                // `remove` instead of `swap_remove`, so switching back to `Stack` finds the free list in the same order.
+                lowest.map(|pos| self.free.remove(pos))
+            },
+        };
+        match next_free {
             Some(index) => {
                 self.generation_counter += 1;
                 self.entries[index as usize].generation = self.generation_counter;
                 self.entries[index as usize].is_live = true;
+                self.entries[index as usize].allocated_at = self.current_frame;
+                self.total_allocated = self.total_allocated.wrapping_add(1);
                 Ok(GenerationalIndex{
-                    index: index as IndexType,
-                    generation: self.generation_counter
+                    index,
+                    generation: self.generation_counter,
+                    world: self.world_id,
                 }) 
             },
             None => Err(AllocatorOutOfMemory(())),
@@ -82,10 +176,37 @@ impl GenerationalIndexAllocator {
 
 
 
+    /// Make exactly this handle (index AND generation) live again, e.g. when loading a save. Since restored handles are
+    /// identical to the saved ones, any handles stored inside saved components stay valid without remapping.
+    /// The slot has to be free. Later allocations always get a newer generation than any restored one.
+    pub fn allocate_at(&mut self, handle: &GenerationalIndex) -> Result<(), AllocateAtError> {
+        let i = handle.index;
+        if handle.world != self.world_id {
+            Err(AllocateAtError::WorldMismatch)
+        } else if i as usize >= self.entries.len() {
+            Err(AllocateAtError::IndexOOB)
+        } else if self.entries[i as usize].is_live {
+            Err(AllocateAtError::AlreadyLive)
+        } else {
+            // Keep the rest of the free list in order, so allocation order stays the same as before.
+            if let Some(pos) = self.free.iter().position(|&f| f == i) {
+                self.free.remove(pos);
+            }
+            self.generation_counter = self.generation_counter.max(handle.generation);
+            self.entries[i as usize].generation = handle.generation;
+            self.entries[i as usize].is_live = true;
+            self.entries[i as usize].allocated_at = self.current_frame;
+            self.total_allocated = self.total_allocated.wrapping_add(1);
+            Ok(())
+        }
+    }
+
     /// Return index back to pool of available ones. This does NOT deallocate the resource itself.
     pub fn deallocate(&mut self, index: &GenerationalIndex) -> Result<(), DeallocationError> {
         let i = index.index;
-        if i >= self.entries.len() as IndexType {
+        if index.world != self.world_id {
+            Err(DeallocationError::WorldMismatch)
+        } else if i as usize >= self.entries.len() {
             Err(DeallocationError::IndexOOB)
         } else if self.entries[i as usize].generation != index.generation {
             Err(DeallocationError::GenerationMismatch)
@@ -94,18 +215,138 @@ impl GenerationalIndexAllocator {
         } else {
             self.entries[i as usize].is_live = false;
             self.free.push(i);
+            self.total_deallocated = self.total_deallocated.wrapping_add(1);
             Ok(())
         }
     }
     
+    /// Debug/editor tool: free whatever live entity occupies this raw slot, without checking the generation.
+    /// Every handle to that slot stops being live. Prefer `deallocate` whenever you have the full handle,
+    /// and remember to drop the entity from your `entities` list as well.
+    pub fn force_deallocate(&mut self, index: IndexType) -> Result<(), DeallocationError> {
+        match self.entries.get_mut(index as usize) {
+            None => Err(DeallocationError::IndexOOB),
+            Some(entry) if !entry.is_live => Err(DeallocationError::AlreadyDeallocated),
+            Some(entry) => {
+                entry.is_live = false;
+                self.free.push(index);
+                self.total_deallocated = self.total_deallocated.wrapping_add(1);
+                Ok(())
+            }
+        }
+    }
+    
     /// Check whether this index is live (i.e. if it was deallocated, the index still exists, but it's not "live").
     pub fn is_live(&self, index: &GenerationalIndex) -> Result<bool, GenerationalIndexError> {
-        if index.index >= self.entries.len() as IndexType {
+        if index.world != self.world_id {
+            Err(GenerationalIndexError::WorldMismatch)
+        } else if index.index as usize >= self.entries.len() {
             Err(GenerationalIndexError::IndexOOB)
         } else {
             Ok(self.entries[index.index as usize].is_live)
         }
     }
+
+    /// Change how the next indices get picked (see `FreeStrategy`). This can be switched at any time.
+    pub fn set_free_strategy(&mut self, strategy: FreeStrategy) {
+        self.free_strategy = strategy;
+    }
+
+    /// Whether every index is in use, so the next `allocate` would fail.
+    pub fn is_full(&self) -> bool {
+        self.free.is_empty()
+    }
+
+    /// Whether `n` more indices can be allocated right now. Check this before spawning a group of entities
+    /// that only makes sense as a whole, instead of spawning some and rolling them back.
+    pub fn can_allocate(&self, n: usize) -> bool {
+        self.free.len() >= n
+    }
+
+    /// Every slot index, with whether it's live right now (e.g. to draw a map of which slots are in use).
+    pub fn iter_slots(&self) -> impl Iterator<Item = (IndexType, bool)> + '_ {
+        self.entries.iter().enumerate().map(|(i, entry)| (i as IndexType, entry.is_live))
+    }
+
+    /// How many entities have been allocated (including with `allocate_at`) since the allocator was made.
+    pub fn total_allocated(&self) -> u32 {
+        self.total_allocated
+    }
+
+    /// How many entities have been deallocated (including with `force_deallocate`) since the allocator was made.
+    /// `total_allocated() - total_deallocated()` should always be the number of live entities.
+    pub fn total_deallocated(&self) -> u32 {
+        self.total_deallocated
+    }
+
+    /// How many indices there are in total (live or not). Every component array should have exactly this many slots.
+    pub fn capacity(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// How many indices are live right now.
+    pub fn live_count(&self) -> usize {
+        self.entries.len() - self.free.len()
+    }
+
+    /// Handles to every live entity, in slot order. The allocator is the source of truth for what's alive, so this is the
+    /// way to recover if a separately kept list of entities drifts out of sync with it. Allocates room for every slot.
+    pub fn rebuild_entities(&self) -> Vec<GenerationalIndex> {
+        let mut entities = Vec::with_capacity(self.entries.len());
+        entities.extend(self.iter_live_by_index());
+        entities
+    }
+
+    /// A handle for every live entity, by increasing slot index. Unlike the order of a world's `entities` (which depends
+    /// on which slots happened to be freed when), this only depends on which slots are live, so it's a stable order
+    /// e.g. to draw in, so overlapping entities don't swap places from one frame to the next.
+    pub fn iter_live_by_index(&self) -> impl DoubleEndedIterator<Item = GenerationalIndex> + '_ {
+        self.entries.iter().enumerate().filter(|(_, entry)| entry.is_live).map(|(i, entry)| GenerationalIndex {
+            index: i as IndexType,
+            generation: entry.generation,
+            world: self.world_id,
+        })
+    }
+
+    /// Tell the allocator which frame it is, so entities allocated from now on are stamped with it (see `age`).
+    pub fn set_frame(&mut self, frame: u32) {
+        self.current_frame = frame;
+    }
+
+    /// How many frames ago this entity was allocated, or `None` if the handle isn't live.
+    /// This only works if `set_frame` is called every frame.
+    pub fn age(&self, index: &GenerationalIndex, current_frame: u32) -> Option<u32> {
+        match self.is_live(index) {
+            Ok(true) => {
+                let entry = &self.entries[index.index as usize];
+                if entry.generation == index.generation {
+                    Some(current_frame.wrapping_sub(entry.allocated_at))
+                } else {
+                    None
+                }
+            },
+            _ => None,
+        }
+    }
+
+    /// Where this entity falls in the order entities were created, or `None` if the handle isn't live: later entities always
+    /// get a bigger number, even ones allocated on the same frame, so sorting by it gives a strict creation order
+    /// (unlike `age`). This is the handle's generation, since every allocation takes the next one from a shared counter.
+    /// Entities restored with `allocate_at` keep their saved generation, and so their place in the order.
+    pub fn spawn_seq(&self, index: &GenerationalIndex) -> Option<GenerationType> {
+        match self.is_live(index) {
+            Ok(true) if self.entries[index.index as usize].generation == index.generation => Some(index.generation),
+            _ => None,
+        }
+    }
+
+    /// Get the current generation of a raw slot index, if that slot is live. Useful to rebuild a handle from a stored raw index.
+    pub fn generation_at(&self, index: IndexType) -> Option<GenerationType> {
+        match self.entries.get(index as usize) {
+            Some(entry) if entry.is_live => Some(entry.generation),
+            _ => None,
+        }
+    }
 }
 
 // An associative array from GenerationalIndex to some Value T. Since get, set, and get_mut require the allocator to be passed in,
@@ -113,9 +354,23 @@ impl GenerationalIndexAllocator {
 pub struct GenerationalIndexArray<T>(pub Vec<T>);
 
 impl<T> GenerationalIndexArray<T> {
+    /// An array with `n` slots, all set to `value`. `n` should be the allocator's capacity (see `assert_parity`).
+    pub fn filled(n: usize, value: T) -> Self
+    where
+        T: Clone,
+    {
+        GenerationalIndexArray(vec![value; n])
+    }
+
+    /// Panic unless this array has exactly one slot per index of `allocator`. Call it while setting up, so a mismatch shows
+    /// up right away instead of as `IndexOOB` errors (or values that are never read) once the game is running.
+    pub fn assert_parity(&self, allocator: &GenerationalIndexAllocator) {
+        assert_eq!(self.0.len(), allocator.capacity(), "component array size doesn't match the allocator's capacity");
+    }
+
     // Set the value for some generational index, the generation must match AND this index must be live in the passed-in allocator.
     pub fn set(&mut self, index: &GenerationalIndex, allocator: &GenerationalIndexAllocator, value: T) -> Result<(), GenerationalIndexError> {
-        if index.index >= self.0.len() as IndexType {
+        if index.index as usize >= self.0.len() {
             Err(GenerationalIndexError::IndexOOB)
         } else {
             match allocator.is_live(&index) {
@@ -137,7 +392,7 @@ impl<T> GenerationalIndexArray<T> {
 
     /// Gets the value for some generational index, the generation must match AND this index must be live in the passed-in allocator.
     pub fn get(&self, index: &GenerationalIndex, allocator: &GenerationalIndexAllocator) -> Result<&T, GenerationalIndexError> {
-        if index.index >= self.0.len() as IndexType {
+        if index.index as usize >= self.0.len() {
             Err(GenerationalIndexError::IndexOOB)
         } else {
             match allocator.is_live(&index) {
@@ -159,7 +414,7 @@ impl<T> GenerationalIndexArray<T> {
 
     /// Mutably gets the value for some generational index, the generation must match AND this index must be live in the passed-in allocator.
     pub fn get_mut(&mut self, index: &GenerationalIndex, allocator: &GenerationalIndexAllocator) -> Result<&mut T, GenerationalIndexError> {
-        if index.index >= self.0.len() as IndexType {
+        if index.index as usize >= self.0.len() {
             Err(GenerationalIndexError::IndexOOB)
         } else {
             match allocator.is_live(&index) {
@@ -176,8 +431,449 @@ impl<T> GenerationalIndexArray<T> {
                 }
                 Err(e) => Err(e),
             }
-        }   
+        }
     }
+
+    /// Set the same value for many generational indices at once (e.g. a whole wave of spawned entities).
+    /// All-or-nothing: every index is checked first, and if any of them is invalid, nothing is written.
+    pub fn set_many(&mut self, indices: &[GenerationalIndex], allocator: &GenerationalIndexAllocator, value: T) -> Result<(), GenerationalIndexError>
+    where
+        T: Clone,
+    {
+        for index in indices {
+            self.get(index, allocator)?;
+        }
+        for index in indices {
+            self.0[index.index as usize] = value.clone();
+        }
+        Ok(())
+    }
+
+    /// Apply `f` to the value of every live entity in `entities`, for transforms that don't need to know which entity it is.
+    /// Entities that aren't live (or have no value here) are skipped.
+    pub fn map_in_place<F: FnMut(&mut T)>(&mut self, entities: &[GenerationalIndex], allocator: &GenerationalIndexAllocator, mut f: F) {
+        for e in entities {
+            if let Ok(value) = self.get_mut(e, allocator) {
+                f(value);
+            }
+        }
+    }
+
+    /// Whether the value of any live entity in `entities` matches `pred`. Stops at the first match, so it's cheaper than
+    /// counting when only existence matters.
+    pub fn any_where<F: Fn(&T) -> bool>(&self, entities: &[GenerationalIndex], allocator: &GenerationalIndexAllocator, pred: F) -> bool {
+        entities.iter().any(|e| self.get(e, allocator).is_ok_and(&pred))
+    }
+
+    /// Every live slot's raw index with its value, in slot order (e.g. for an inspector keyed by slot).
+    /// Use `GenerationalIndexAllocator::generation_at` to turn a slot back into a full handle.
+    pub fn iter_live_slots<'a>(&'a self, allocator: &'a GenerationalIndexAllocator) -> impl Iterator<Item = (IndexType, &'a T)> + 'a {
+        self.0.iter().zip(allocator.entries.iter()).enumerate()
+            .filter(|(_, (_, entry))| entry.is_live)
+            .map(|(i, (value, _))| (i as IndexType, value))
+    }
+
+    /// The live entities in `entities` whose value crossed `threshold` (in either direction) since last frame, for
+    /// event-on-crossing logic like playing a sound when a ball drops past a line. `value` gives each component's previous
+    /// and current value (e.g. `|k| (k.y - k.vy, k.y)`). Reaching the threshold counts as crossing it, so a value that
+    /// moves past it once is reported on exactly one frame.
+    pub fn crossed<'a, F>(&'a self, entities: &'a [GenerationalIndex], allocator: &'a GenerationalIndexAllocator, threshold: f32, value: F) -> impl Iterator<Item = GenerationalIndex> + 'a
+    where
+        F: Fn(&T) -> (f32, f32) + 'a,
+    {
+        entities.iter().copied().filter(move |e| match self.get(e, allocator) {
+            Ok(component) => {
+                let (previous, current) = value(component);
+                (previous < threshold) != (current < threshold)
+            },
+            Err(_) => false,
+        })
+    }
+
+    /// Mutably get the values for two different generational indices at once (each checked like `get_mut`), e.g. to push a
+    /// pair of entities apart. Fails with `SameIndex` if both handles are for the same slot.
+    pub fn get2_mut(&mut self, a: &GenerationalIndex, b: &GenerationalIndex, allocator: &GenerationalIndexAllocator) -> Result<(&mut T, &mut T), GenerationalIndexError> {
+        self.get(a, allocator)?;
+        self.get(b, allocator)?;
+        let (i, j) = (a.index as usize, b.index as usize);
+        if i == j {
+            return Err(GenerationalIndexError::SameIndex);
+        }
+        // Split between the two slots, so each half holds one of them.
+        let (low, high) = self.0.split_at_mut(i.max(j));
+        let (first, second) = (&mut low[i.min(j)], &mut high[0]);
+        if i < j {
+            Ok((first, second))
+        } else {
+            Ok((second, first))
+        }
+    }
+
+    /// Mutably get the value for one generational index (checked like `get_mut`), plus read-only access to every other value
+    /// in the array at the same time. The array is split around the index's slot, so the two borrows never overlap.
+    pub fn get_mut_and_others(&mut self, index: &GenerationalIndex, allocator: &GenerationalIndexAllocator) -> Result<(&mut T, OtherComponents<'_, T>), GenerationalIndexError> {
+        self.get(index, allocator)?;
+        let (before, rest) = self.0.split_at_mut(index.index as usize);
+        let (value, after) = rest.split_first_mut().ok_or(GenerationalIndexError::IndexOOB)?;
+        Ok((value, OtherComponents { before, after, skip: index.index }))
+    }
+}
+
+// Optional components (`None` for entities that don't have it), which are usually only on a handful of entities.
+impl<T> GenerationalIndexArray<Option<T>> {
+    /// How many live entities have this component (a `Some` value).
+    pub fn count_set(&self, allocator: &GenerationalIndexAllocator) -> usize {
+        self.iter_live_slots(allocator).filter(|(_, value)| value.is_some()).count()
+    }
+
+    /// Like `set`, but a guardrail against a component accidentally getting applied to far more entities than intended:
+    /// giving the component to another entity while `soft_cap` of them already have it fails with `OverSoftCap`,
+    /// and nothing is written. Replacing or removing (`None`) a value always works.
+    /// To only warn instead, log the error and call `set` anyway.
+    pub fn set_capped(&mut self, index: &GenerationalIndex, allocator: &GenerationalIndexAllocator, value: Option<T>, soft_cap: usize) -> Result<(), GenerationalIndexError> {
+        let adds_one = value.is_some() && self.get(index, allocator)?.is_none();
+        if adds_one && self.count_set(allocator) >= soft_cap {
+            return Err(GenerationalIndexError::OverSoftCap);
+        }
+        self.set(index, allocator, value)
+    }
+}
+
+/// Read-only view of every value in a `GenerationalIndexArray` except one slot (see `get_mut_and_others`).
+pub struct OtherComponents<'a, T> {
+    before: &'a [T],
+    after: &'a [T],
+    skip: IndexType,
+}
+
+impl<'a, T> OtherComponents<'a, T> {
+    /// Like `GenerationalIndexArray::get`, but `None` for invalid handles and for the slot that's borrowed mutably.
+    pub fn get(&self, index: &GenerationalIndex, allocator: &GenerationalIndexAllocator) -> Option<&'a T> {
+        match allocator.is_live(index) {
+            Ok(true) if allocator.entries[index.index as usize].generation == index.generation => {
+                if index.index < self.skip {
+                    self.before.get(index.index as usize)
+                } else if index.index > self.skip {
+                    self.after.get((index.index - self.skip - 1) as usize)
+                } else {
+                    None
+                }
+            },
+            _ => None,
+        }
+    }
+}
+
+/// Type-erased view of a component array, so an operation can run over every array in a bundle without naming each field.
+/// See `declare_components!`, which generates `for_each_array` for a bundle struct.
+pub trait ComponentArray {
+    /// Number of slots in the array (this should match the allocator's capacity).
+    fn capacity(&self) -> usize;
+
+    /// Check a handle the way `set` does before writing (live in `allocator`, matching generation, a slot in this array),
+    /// so a `set` with it can't fail.
+    fn check(&self, index: &GenerationalIndex, allocator: &GenerationalIndexAllocator) -> Result<(), GenerationalIndexError>;
+}
+
+impl<T> ComponentArray for GenerationalIndexArray<T> {
+    fn capacity(&self) -> usize {
+        self.0.len()
+    }
+
+    fn check(&self, index: &GenerationalIndex, allocator: &GenerationalIndexAllocator) -> Result<(), GenerationalIndexError> {
+        self.get(index, allocator).map(|_| ())
+    }
+}
+
+/// Component arrays that can be built empty for a given number of entities (see `declare_components!`'s `with_capacity`).
+pub trait WithCapacity {
+    fn with_capacity(capacity: usize) -> Self;
+}
+
+impl<T: Default> WithCapacity for GenerationalIndexArray<T> {
+    /// Every slot starts out as the default value, and the memory is reserved in one go.
+    fn with_capacity(capacity: usize) -> Self {
+        GenerationalIndexArray((0..capacity).map(|_| T::default()).collect())
+    }
+}
+
+/// How many slots a `LazyGenerationalIndexArray` allocates at once.
+pub const LAZY_CHUNK_SIZE: usize = 64;
+
+/// Like `GenerationalIndexArray`, for components that only a few entities have. Slots are grouped into chunks of
+/// `LAZY_CHUNK_SIZE`, and a chunk is only allocated (filled with defaults) the first time one of its slots is `set`.
+/// `get` and `get_mut` check handles exactly like `GenerationalIndexArray`, and return `NotSet` for slots in a chunk
+/// that was never allocated. Note that `reset_entity` sets every component, so it allocates the entity's chunk.
+pub struct LazyGenerationalIndexArray<T> {
+    chunks: Vec<Option<Vec<T>>>,
+    capacity: usize,
+}
+
+impl<T: Default> LazyGenerationalIndexArray<T> {
+    /// `capacity` should match the allocator's capacity. Only the (small) list of chunk pointers is allocated here.
+    pub fn new(capacity: usize) -> LazyGenerationalIndexArray<T> {
+        LazyGenerationalIndexArray {
+            chunks: (0..capacity.div_ceil(LAZY_CHUNK_SIZE)).map(|_| None).collect(),
+            capacity,
+        }
+    }
+
+    /// Set the value for some generational index, allocating its chunk if needed.
+    pub fn set(&mut self, index: &GenerationalIndex, allocator: &GenerationalIndexAllocator, value: T) -> Result<(), GenerationalIndexError> {
+        self.check(index, allocator)?;
+        let i = index.index as usize;
+        let chunk = self.chunks[i / LAZY_CHUNK_SIZE].get_or_insert_with(|| (0..LAZY_CHUNK_SIZE).map(|_| T::default()).collect());
+        chunk[i % LAZY_CHUNK_SIZE] = value;
+        Ok(())
+    }
+
+    pub fn get(&self, index: &GenerationalIndex, allocator: &GenerationalIndexAllocator) -> Result<&T, GenerationalIndexError> {
+        self.check(index, allocator)?;
+        let i = index.index as usize;
+        match &self.chunks[i / LAZY_CHUNK_SIZE] {
+            Some(chunk) => Ok(&chunk[i % LAZY_CHUNK_SIZE]),
+            None => Err(GenerationalIndexError::NotSet),
+        }
+    }
+
+    pub fn get_mut(&mut self, index: &GenerationalIndex, allocator: &GenerationalIndexAllocator) -> Result<&mut T, GenerationalIndexError> {
+        self.check(index, allocator)?;
+        let i = index.index as usize;
+        match &mut self.chunks[i / LAZY_CHUNK_SIZE] {
+            Some(chunk) => Ok(&mut chunk[i % LAZY_CHUNK_SIZE]),
+            None => Err(GenerationalIndexError::NotSet),
+        }
+    }
+
+    /// How many chunks have been allocated so far.
+    pub fn allocated_chunks(&self) -> usize {
+        self.chunks.iter().filter(|chunk| chunk.is_some()).count()
+    }
+}
+
+impl<T> ComponentArray for LazyGenerationalIndexArray<T> {
+    fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    // The same checks `GenerationalIndexArray` does before touching a slot. Unlike `get`, this passes for a slot whose
+    // chunk isn't allocated yet, since `set` allocates it.
+    fn check(&self, index: &GenerationalIndex, allocator: &GenerationalIndexAllocator) -> Result<(), GenerationalIndexError> {
+        if index.index as usize >= self.capacity {
+            Err(GenerationalIndexError::IndexOOB)
+        } else if !allocator.is_live(index)? {
+            Err(GenerationalIndexError::NotLive)
+        } else if allocator.entries[index.index as usize].generation != index.generation {
+            Err(GenerationalIndexError::GenerationMismatch)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl<T: Default> WithCapacity for LazyGenerationalIndexArray<T> {
+    fn with_capacity(capacity: usize) -> Self {
+        LazyGenerationalIndexArray::new(capacity)
+    }
+}
+
+/// Components that own heap memory (e.g. a buffer), which is worth keeping around for the next entity instead of freeing
+/// it on despawn and allocating it again on spawn. See `PooledGenerationalIndexArray`.
+pub trait Poolable: Default {
+    /// Empty the value for reuse, keeping its heap memory (e.g. `Vec::clear`).
+    fn reset(&mut self);
+}
+
+impl<T> Poolable for Vec<T> {
+    fn reset(&mut self) {
+        self.clear();
+    }
+}
+
+/// Like `GenerationalIndexArray`, for `Poolable` components. Call `release` for an entity right before despawning it, which
+/// moves its value into a free pool (leaving a default behind, which doesn't allocate), and `acquire` after spawning one,
+/// which hands back a pooled value (already `reset`) instead of making a fresh one. Values are never freed, so the pool only
+/// ever holds as many values as were live at once.
+pub struct PooledGenerationalIndexArray<T: Poolable> {
+    values: GenerationalIndexArray<T>,
+    pool: Vec<T>,
+}
+
+impl<T: Poolable> PooledGenerationalIndexArray<T> {
+    pub fn new(capacity: usize) -> PooledGenerationalIndexArray<T> {
+        PooledGenerationalIndexArray {
+            values: GenerationalIndexArray::with_capacity(capacity),
+            pool: vec![],
+        }
+    }
+
+    /// Give a live entity a value from the pool, and return it (`reset`) to be filled in. If the pool is empty, the slot's
+    /// own value is reset and handed back instead, so a slot that was never released doesn't leak the last entity's data.
+    pub fn acquire(&mut self, index: &GenerationalIndex, allocator: &GenerationalIndexAllocator) -> Result<&mut T, GenerationalIndexError> {
+        let slot = self.values.get_mut(index, allocator)?;
+        match self.pool.pop() {
+            Some(mut value) => {
+                value.reset();
+                // Whatever was in the slot before gets dropped, but that's a default unless the slot was never released.
+                *slot = value;
+            },
+            None => slot.reset(),
+        }
+        Ok(slot)
+    }
+
+    /// Move a live entity's value into the pool. Do this before despawning it, while its handle still checks out.
+    pub fn release(&mut self, index: &GenerationalIndex, allocator: &GenerationalIndexAllocator) -> Result<(), GenerationalIndexError> {
+        let value = core::mem::take(self.values.get_mut(index, allocator)?);
+        self.pool.push(value);
+        Ok(())
+    }
+
+    /// How many values are waiting in the pool.
+    pub fn pooled(&self) -> usize {
+        self.pool.len()
+    }
+
+    pub fn set(&mut self, index: &GenerationalIndex, allocator: &GenerationalIndexAllocator, value: T) -> Result<(), GenerationalIndexError> {
+        self.values.set(index, allocator, value)
+    }
+
+    pub fn get(&self, index: &GenerationalIndex, allocator: &GenerationalIndexAllocator) -> Result<&T, GenerationalIndexError> {
+        self.values.get(index, allocator)
+    }
+
+    pub fn get_mut(&mut self, index: &GenerationalIndex, allocator: &GenerationalIndexAllocator) -> Result<&mut T, GenerationalIndexError> {
+        self.values.get_mut(index, allocator)
+    }
+}
+
+impl<T: Poolable> ComponentArray for PooledGenerationalIndexArray<T> {
+    fn capacity(&self) -> usize {
+        self.values.capacity()
+    }
+
+    fn check(&self, index: &GenerationalIndex, allocator: &GenerationalIndexAllocator) -> Result<(), GenerationalIndexError> {
+        self.values.check(index, allocator)
+    }
+}
+
+impl<T: Poolable> WithCapacity for PooledGenerationalIndexArray<T> {
+    fn with_capacity(capacity: usize) -> Self {
+        PooledGenerationalIndexArray::new(capacity)
+    }
+}
+
+/// A struct holding all of the component arrays of a world (implemented by `declare_components!`).
+pub trait ComponentBundle {
+    /// Move every component of `entity` over to `to_entity` in another bundle of the same type, leaving defaults behind.
+    /// All-or-nothing: if any component can't be moved (e.g. a `LazyGenerationalIndexArray` that was never set for
+    /// `entity`), nothing is.
+    fn move_entity(&mut self, entity: &Entity, allocator: &GenerationalIndexAllocator, to: &mut Self, to_entity: &Entity, to_allocator: &GenerationalIndexAllocator) -> Result<(), GenerationalIndexError>;
+}
+
+/// Declare the struct holding all of your component arrays. Besides the struct itself, this generates
+/// `with_capacity`, which builds every array with room for that many entities (reserved in the order the fields are declared,
+/// so declare the largest components first), `for_each_array`, which visits every array (with its field name),
+/// so you don't have to touch each field by hand,
+/// `reset_entity`, which writes every component of an entity back to its default (so components need `Default`),
+/// and an impl of `ComponentBundle`, so entities can be moved between worlds with `migrate`.
+///
+/// ```ignore
+/// declare_components! {
+///     struct EntityComponents {
+///         kinematics: EntityMap<Kinematics>,
+///         physics: EntityMap<PhysicsComponent>,
+///     }
+/// }
+/// ```
+#[macro_export]
+macro_rules! declare_components {
+    ($(#[$meta:meta])* $vis:vis struct $name:ident { $($field_vis:vis $field:ident : $ty:ty),* $(,)? }) => {
+        $(#[$meta])*
+        $vis struct $name {
+            $($field_vis $field: $ty,)*
+        }
+
+        #[allow(dead_code)]
+        impl $name {
+            /// Every component array with `capacity` slots of default values. The arrays are reserved in declaration order.
+            $vis fn with_capacity(capacity: usize) -> Self {
+                $name {
+                    $($field: $crate::ecs::WithCapacity::with_capacity(capacity),)*
+                }
+            }
+
+            /// Visit every component array in this bundle, along with its field name.
+            $vis fn for_each_array<F: FnMut(&'static str, &mut dyn $crate::ecs::ComponentArray)>(&mut self, mut f: F) {
+                $(f(stringify!($field), &mut self.$field);)*
+            }
+
+            /// Write every component of a live entity back to its default value, without despawning it.
+            /// The entity keeps its handle, so anything holding on to it stays valid ("respawn in place").
+            $vis fn reset_entity(&mut self, entity: &$crate::ecs::Entity, allocator: &$crate::ecs::GenerationalIndexAllocator) -> Result<(), $crate::ecs::GenerationalIndexError> {
+                $(self.$field.set(entity, allocator, Default::default())?;)*
+                Ok(())
+            }
+        }
+
+        impl $crate::ecs::ComponentBundle for $name {
+            fn move_entity(&mut self, entity: &$crate::ecs::Entity, allocator: &$crate::ecs::GenerationalIndexAllocator, to: &mut Self, to_entity: &$crate::ecs::Entity, to_allocator: &$crate::ecs::GenerationalIndexAllocator) -> Result<(), $crate::ecs::GenerationalIndexError> {
+                // Check every component on both sides before taking any, so a failure can't lose the ones already moved.
+                $(self.$field.get(entity, allocator)?;)*
+                $($crate::ecs::ComponentArray::check(&to.$field, to_entity, to_allocator)?;)*
+                $(to.$field.set(to_entity, to_allocator, core::mem::take(self.$field.get_mut(entity, allocator)?))?;)*
+                Ok(())
+            }
+        }
+    };
+}
+
+/// Everything a `World` needs besides its resources, for a component bundle declared with `declare_components!`:
+/// `(allocator, components, entities)`, all with room for `capacity` entities and nothing spawned yet.
+/// Memory is reserved in a fixed order: the component arrays (in declaration order), the entities list, then the allocator.
+///
+/// ```ignore
+/// let (allocator, components, entities) = ecs_init!(EntityComponents, MAX_N_ENTITIES);
+/// let world = World::new(0, allocator, components, resources, entities);
+/// ```
+#[macro_export]
+macro_rules! ecs_init {
+    ($components:ty, $capacity:expr) => {{
+        let capacity: usize = $capacity;
+        let components = <$components>::with_capacity(capacity);
+        let entities: Vec<$crate::ecs::Entity> = Vec::with_capacity(capacity);
+        let entries = (0..capacity).map(|_| $crate::ecs::AllocatorEntry::new()).collect();
+        let free = (0..capacity).map(|i| i as $crate::ecs::IndexType).collect();
+        ($crate::ecs::GenerationalIndexAllocator::new(entries, free), components, entities)
+    }};
+}
+
+/// Get several components of one entity at once, as `Some` tuple of references, or `None` if any of them is missing
+/// (dead entity, stale handle, or not set). Put `mut` before the first array to get that one mutably.
+/// The arrays have to be different fields, so their borrows don't overlap.
+///
+/// ```ignore
+/// if let Some((pos, phys)) = fetch!(&e, &ecs.entity_allocator; mut ecs.components.kinematics, ecs.components.physics) {
+///     pos.vx *= 1.0 - phys.damping;
+/// }
+/// ```
+#[macro_export]
+macro_rules! fetch {
+    ($entity:expr, $allocator:expr; mut $first:expr $(, $rest:expr)* $(,)?) => {
+        'fetch: {
+            let (entity, allocator) = ($entity, $allocator);
+            Some((
+                match $first.get_mut(entity, allocator) { Ok(c) => c, Err(_) => break 'fetch None },
+                $(match $rest.get(entity, allocator) { Ok(c) => c, Err(_) => break 'fetch None },)*
+            ))
+        }
+    };
+    ($entity:expr, $allocator:expr; $($array:expr),+ $(,)?) => {
+        'fetch: {
+            let (entity, allocator) = ($entity, $allocator);
+            Some(($(match $array.get(entity, allocator) { Ok(c) => c, Err(_) => break 'fetch None },)+))
+        }
+    };
 }
 
 // We're dropping the index or id suffix, because there is no other "Entity"
@@ -189,3 +885,663 @@ pub type Entity = GenerationalIndex;
 // Map of Entity to some type T
 pub type EntityMap<T> = GenerationalIndexArray<T>;
 
+/// A self-contained set of entities: its own allocator, a bundle of component arrays `C`, and resources `R`.
+/// Each world stamps its id into the handles it hands out, so an entity from one world never validates against another.
+/// Like the allocator, the `entities` vector is passed in so its memory is reserved explicitly by the caller.
+pub struct World<C, R> {
+    pub entity_allocator: GenerationalIndexAllocator,
+    pub components: C,
+    pub resources: R,
+    pub entities: Vec<Entity>,
+}
+
+impl<C, R> World<C, R> {
+    pub fn new(id: WorldIdType, mut entity_allocator: GenerationalIndexAllocator, components: C, resources: R, entities: Vec<Entity>) -> World<C, R> {
+        entity_allocator.world_id = id;
+        World {
+            entity_allocator,
+            components,
+            resources,
+            entities,
+        }
+    }
+
+    /// Allocate a new entity and start tracking it in `entities`. Its components still need to be set afterwards.
+    pub fn spawn(&mut self) -> Result<Entity, AllocatorOutOfMemory> {
+        let entity = self.entity_allocator.allocate()?;
+        self.entities.push(entity);
+        Ok(entity)
+    }
+
+    /// Make a specific handle live again and add it to `entities` (see `GenerationalIndexAllocator::allocate_at`).
+    pub fn spawn_at(&mut self, entity: &Entity) -> Result<(), AllocateAtError> {
+        self.entity_allocator.allocate_at(entity)?;
+        self.entities.push(*entity);
+        Ok(())
+    }
+
+    /// Allocate an entity and set its components with `init`. The entity is only pushed to `entities` once `init` succeeded;
+    /// if any component set fails, the allocation is rolled back so no half-initialized entity is left behind.
+    pub fn spawn_with_components<F>(&mut self, init: F) -> Result<Entity, SpawnError>
+    where
+        F: FnOnce(&mut C, &Entity, &GenerationalIndexAllocator) -> Result<(), GenerationalIndexError>,
+    {
+        let entity = match self.entity_allocator.allocate() {
+            Ok(entity) => entity,
+            Err(_) => return Err(SpawnError::OutOfMemory),
+        };
+        match init(&mut self.components, &entity, &self.entity_allocator) {
+            Ok(()) => {
+                self.entities.push(entity);
+                Ok(entity)
+            },
+            Err(e) => {
+                // The entity was allocated just above, so this can't fail.
+                let _ = self.entity_allocator.deallocate(&entity);
+                Err(SpawnError::ComponentSet(e))
+            }
+        }
+    }
+
+    /// Deallocate an entity and stop tracking it. The component values stay in their arrays until the slot is reused.
+    pub fn despawn(&mut self, entity: &Entity) -> Result<(), DeallocationError> {
+        self.entity_allocator.deallocate(entity)?;
+        self.entities.retain(|e| e != entity);
+        Ok(())
+    }
+
+    /// Check whether a handle refers to a live entity of this world (right world, live slot, matching generation).
+    pub fn is_alive(&self, entity: &Entity) -> bool {
+        match self.entity_allocator.is_live(entity) {
+            Ok(true) => self.entity_allocator.entries[entity.index as usize].generation == entity.generation,
+            _ => false,
+        }
+    }
+
+    /// Rebuild `entities` from the allocator if it doesn't hold exactly the live entities (e.g. something deallocated an
+    /// entity but forgot to remove it from the list, or swapped in a stale handle). Returns whether it had to.
+    /// Debug builds also catch a handle that's in the list twice (see `has_duplicate_entities`).
+    pub fn resync_entities(&mut self) -> bool {
+        let in_sync = self.entities.len() == self.entity_allocator.live_count()
+            && self.entities.iter().all(|e| self.is_alive(e))
+            && !(cfg!(debug_assertions) && has_duplicate_entities(&self.entities));
+        if in_sync {
+            return false;
+        }
+        self.entities = self.entity_allocator.rebuild_entities();
+        true
+    }
+
+    /// Borrow the components mutably, the allocator, and the resources mutably all at once, so a system can e.g. add
+    /// up something into a resource while going through the components, without fighting the borrow checker.
+    pub fn components_and_resources_mut(&mut self) -> (&mut C, &GenerationalIndexAllocator, &mut R) {
+        (&mut self.components, &self.entity_allocator, &mut self.resources)
+    }
+
+    /// Iterate over every tracked entity that has a value in the given component array.
+    pub fn query<'a, T>(&'a self, array: &'a EntityMap<T>) -> impl Iterator<Item = (Entity, &'a T)> + 'a {
+        self.entities.iter().filter_map(move |e| array.get(e, &self.entity_allocator).ok().map(|v| (*e, v)))
+    }
+}
+
+/// Whether the same handle shows up more than once in `entities`, which would make systems process that entity twice.
+/// It compares every pair instead of using a set, so it never touches the heap, but that's O(n²): it's a debug-build check
+/// (`resync_entities` skips it in release builds).
+pub fn has_duplicate_entities(entities: &[Entity]) -> bool {
+    entities.iter().enumerate().any(|(i, e)| entities[..i].contains(e))
+}
+
+/// Move an entity, with all of its components, from one world to another (with the same component bundle), returning its
+/// handle in `to`. The entity is despawned in `from`. Handles differ between worlds, so any handles stored in components
+/// (e.g. links) still point into `from`, and the caller has to remap them.
+pub fn migrate<C: ComponentBundle, R1, R2>(entity: &Entity, from: &mut World<C, R1>, to: &mut World<C, R2>) -> Result<Entity, SpawnError> {
+    // Check first, so a stale handle doesn't spawn anything in `to`.
+    if !from.is_alive(entity) {
+        return Err(SpawnError::ComponentSet(GenerationalIndexError::NotLive));
+    }
+    let moved = to.spawn_with_components(|c, new_entity, alloc| from.components.move_entity(entity, &from.entity_allocator, c, new_entity, alloc))?;
+    // The entity was checked to be alive above, so this can't fail.
+    let _ = from.despawn(entity);
+    Ok(moved)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // An allocator with `n` free slots, like `ecs_init!` makes.
+    fn allocator(n: usize) -> GenerationalIndexAllocator {
+        GenerationalIndexAllocator::new((0..n).map(|_| AllocatorEntry::new()).collect(), (0..n).map(|i| i as IndexType).collect())
+    }
+
+    fn world(id: WorldIdType, n: usize) -> World<(), ()> {
+        World::new(id, allocator(n), (), (), Vec::with_capacity(n))
+    }
+
+    declare_components! {
+        struct TestComponents {
+            small: EntityMap<u8>,
+            big: EntityMap<u32>,
+        }
+    }
+
+    declare_components! {
+        struct MixedComponents {
+            common: EntityMap<u8>,
+            rare: LazyGenerationalIndexArray<u8>,
+        }
+    }
+
+    #[test]
+    fn handles_from_one_world_dont_validate_in_another() {
+        let mut a = world(0, 4);
+        let mut b = world(1, 4);
+        let ea = a.spawn().ok().unwrap();
+        let eb = b.spawn().ok().unwrap();
+        // Both worlds hand out the same slot and generation, so only the world id tells the handles apart.
+        assert!(a.is_alive(&ea) && b.is_alive(&eb));
+        assert!(!a.is_alive(&eb));
+        assert!(!b.is_alive(&ea));
+        let values: EntityMap<u8> = GenerationalIndexArray::filled(4, 0);
+        assert!(matches!(values.get(&eb, &a.entity_allocator), Err(GenerationalIndexError::WorldMismatch)));
+        assert!(matches!(b.despawn(&ea), Err(DeallocationError::WorldMismatch)));
+        assert!(b.is_alive(&eb));
+    }
+
+    #[test]
+    fn for_each_array_visits_every_array_in_order() {
+        let mut components = TestComponents::with_capacity(8);
+        let mut visited = vec![];
+        components.for_each_array(|name, array| visited.push((name, array.capacity())));
+        assert_eq!(visited, [("small", 8), ("big", 8)]);
+    }
+
+    #[test]
+    fn failed_component_set_leaves_no_half_spawned_entity() {
+        let mut w = World::new(0, allocator(4), TestComponents::with_capacity(4), (), vec![]);
+        let stale = w.spawn().ok().unwrap();
+        w.despawn(&stale).unwrap();
+        let spawned = w.spawn_with_components(|c, e, alloc| {
+            c.small.set(e, alloc, 1)?;
+            // Injected failure: the second set goes through a handle that's no longer live.
+            c.big.set(&stale, alloc, 2)
+        });
+        assert!(matches!(spawned, Err(SpawnError::ComponentSet(GenerationalIndexError::GenerationMismatch))));
+        assert!(w.entities.is_empty());
+        assert_eq!(w.entity_allocator.live_count(), 0);
+        assert_eq!(w.entity_allocator.total_allocated(), w.entity_allocator.total_deallocated());
+    }
+
+    #[test]
+    fn generation_at_is_the_live_generation_or_none() {
+        let mut a = allocator(3);
+        let first = a.allocate().ok().unwrap();
+        a.deallocate(&first).unwrap();
+        let second = a.allocate().ok().unwrap();
+        assert_eq!(second.index, first.index);
+        assert_eq!(a.generation_at(second.index), Some(second.generation));
+        assert_ne!(a.generation_at(second.index), Some(first.generation));
+        a.deallocate(&second).unwrap();
+        assert_eq!(a.generation_at(second.index), None);
+        // Never allocated, and out of range.
+        assert_eq!(a.generation_at(1), None);
+        assert_eq!(a.generation_at(3), None);
+    }
+
+    #[test]
+    fn force_deallocate_frees_a_slot_by_raw_index() {
+        let mut a = allocator(2);
+        let e = a.allocate().ok().unwrap();
+        a.force_deallocate(e.index).unwrap();
+        assert!(!a.is_live(&e).unwrap());
+        assert!(matches!(a.deallocate(&e), Err(DeallocationError::AlreadyDeallocated)));
+        assert!(matches!(a.force_deallocate(e.index), Err(DeallocationError::AlreadyDeallocated)));
+        assert!(matches!(a.force_deallocate(2), Err(DeallocationError::IndexOOB)));
+        // The slot gets reused with a new generation, so the old handle stays invalid.
+        let reused = a.allocate().ok().unwrap();
+        assert_eq!(reused.index, e.index);
+        let values: EntityMap<u8> = GenerationalIndexArray::filled(2, 0);
+        assert!(matches!(values.get(&e, &a), Err(GenerationalIndexError::GenerationMismatch)));
+    }
+
+    // Run with and without the `index-u8` (and `generation-u64`) features to cover each handle size.
+    #[test]
+    fn every_index_of_the_index_type_is_usable() {
+        let n = IndexType::MAX as usize + 1;
+        let mut a = allocator(n);
+        let values: EntityMap<u8> = GenerationalIndexArray::filled(n, 7);
+        let mut indices = vec![];
+        while let Ok(e) = a.allocate() {
+            assert!(GenerationalIndex::from_raw(e.to_raw()) == e);
+            assert_eq!(*values.get(&e, &a).unwrap(), 7);
+            indices.push(e.index as usize);
+        }
+        indices.sort();
+        assert!(indices.into_iter().eq(0..n));
+        assert_eq!(a.iter_live_by_index().last().map(|e| e.index), Some(IndexType::MAX));
+    }
+
+    #[test]
+    fn reset_entity_restores_defaults_and_keeps_the_entity() {
+        let mut w = World::new(0, allocator(2), TestComponents::with_capacity(2), (), vec![]);
+        let e = w.spawn_with_components(|c, e, alloc| {
+            c.small.set(e, alloc, 3)?;
+            c.big.set(e, alloc, 4)
+        }).unwrap();
+        w.components.reset_entity(&e, &w.entity_allocator).unwrap();
+        assert!(w.is_alive(&e));
+        assert!(w.entities == [e]);
+        assert_eq!(*w.components.small.get(&e, &w.entity_allocator).unwrap(), 0);
+        assert_eq!(*w.components.big.get(&e, &w.entity_allocator).unwrap(), 0);
+    }
+
+    #[test]
+    fn age_counts_frames_since_allocation() {
+        let mut a = allocator(1);
+        a.set_frame(10);
+        let e = a.allocate().ok().unwrap();
+        for frame in 10..15 {
+            assert_eq!(a.age(&e, frame), Some(frame - 10));
+        }
+        // Reusing the slot starts over, and the old handle has no age anymore.
+        a.deallocate(&e).unwrap();
+        a.set_frame(20);
+        let reused = a.allocate().ok().unwrap();
+        assert_eq!(a.age(&reused, 21), Some(1));
+        assert_eq!(a.age(&e, 21), None);
+    }
+
+    #[test]
+    fn set_many_writes_nothing_if_any_handle_is_invalid() {
+        let mut a = allocator(4);
+        let live: Vec<_> = (0..3).map(|_| a.allocate().ok().unwrap()).collect();
+        let dead = live[1];
+        a.deallocate(&dead).unwrap();
+        let mut values: EntityMap<u8> = GenerationalIndexArray::filled(4, 0);
+        assert!(matches!(values.set_many(&live, &a, 9), Err(GenerationalIndexError::NotLive)));
+        assert_eq!(values.0, [0; 4]);
+        let still_live = [live[0], live[2]];
+        values.set_many(&still_live, &a, 9).unwrap();
+        assert!(still_live.iter().all(|e| *values.get(e, &a).unwrap() == 9));
+    }
+
+    #[test]
+    fn map_in_place_transforms_live_values_and_skips_dead_ones() {
+        let mut a = allocator(3);
+        let entities: Vec<_> = (0..3).map(|_| a.allocate().ok().unwrap()).collect();
+        let mut velocities: EntityMap<f32> = GenerationalIndexArray::filled(3, 2.0);
+        a.deallocate(&entities[1]).unwrap();
+        velocities.map_in_place(&entities, &a, |v| *v *= 0.5);
+        assert_eq!(*velocities.get(&entities[0], &a).unwrap(), 1.0);
+        assert_eq!(*velocities.get(&entities[2], &a).unwrap(), 1.0);
+        assert_eq!(velocities.0[entities[1].index as usize], 2.0);
+    }
+
+    #[test]
+    fn is_full_exactly_when_every_slot_is_allocated() {
+        let mut a = allocator(3);
+        let mut handles = vec![];
+        for _ in 0..3 {
+            assert!(!a.is_full());
+            handles.push(a.allocate().ok().unwrap());
+        }
+        assert!(a.is_full());
+        assert!(a.allocate().is_err());
+        a.deallocate(&handles[1]).unwrap();
+        assert!(!a.is_full());
+    }
+
+    #[test]
+    fn can_allocate_at_various_fill_levels() {
+        let mut a = allocator(4);
+        for used in 0..=4 {
+            for n in 0..=5 {
+                assert_eq!(a.can_allocate(n), n <= 4 - used, "{} used, {} more", used, n);
+            }
+            let _ = a.allocate();
+        }
+    }
+
+    #[test]
+    fn migrate_moves_every_component_to_the_other_world() {
+        let mut from = World::new(0, allocator(4), MixedComponents::with_capacity(4), (), vec![]);
+        let mut to = World::new(1, allocator(4), MixedComponents::with_capacity(4), (), vec![]);
+        let e = from.spawn_with_components(|c, e, alloc| {
+            c.common.set(e, alloc, 7)?;
+            c.rare.set(e, alloc, 8)
+        }).unwrap();
+        let moved = migrate(&e, &mut from, &mut to).unwrap();
+        assert!(!from.is_alive(&e) && from.entities.is_empty());
+        assert!(to.is_alive(&moved) && to.entities == [moved]);
+        assert_eq!(*to.components.common.get(&moved, &to.entity_allocator).unwrap(), 7);
+        assert_eq!(*to.components.rare.get(&moved, &to.entity_allocator).unwrap(), 8);
+    }
+
+    #[test]
+    fn failed_migrate_leaves_the_entity_untouched() {
+        let mut from = World::new(0, allocator(4), MixedComponents::with_capacity(4), (), vec![]);
+        let mut to = World::new(1, allocator(4), MixedComponents::with_capacity(4), (), vec![]);
+        // `rare` is never set, so it has nothing to move.
+        let e = from.spawn_with_components(|c, e, alloc| c.common.set(e, alloc, 7)).unwrap();
+        assert!(matches!(migrate(&e, &mut from, &mut to), Err(SpawnError::ComponentSet(GenerationalIndexError::NotSet))));
+        assert!(from.is_alive(&e));
+        assert_eq!(*from.components.common.get(&e, &from.entity_allocator).unwrap(), 7);
+        assert!(to.entities.is_empty());
+        assert_eq!(to.entity_allocator.live_count(), 0);
+    }
+
+    #[test]
+    fn components_and_resources_mut_lets_a_system_add_up_into_a_resource() {
+        let mut world: World<TestComponents, u32> = World::new(0, allocator(4), TestComponents::with_capacity(4), 0, vec![]);
+        for value in [10, 20, 30] {
+            world.spawn_with_components(|c, e, alloc| c.big.set(e, alloc, value)).ok().unwrap();
+        }
+        let entities = world.entities.clone();
+        let (components, allocator, total) = world.components_and_resources_mut();
+        for e in &entities {
+            let big = components.big.get_mut(e, allocator).unwrap();
+            *big += 1;
+            *total += *big;
+        }
+        assert_eq!(world.resources, 63);
+    }
+
+    #[test]
+    fn lazy_array_allocates_a_chunk_only_when_a_slot_in_it_is_set() {
+        let n = LAZY_CHUNK_SIZE * 2;
+        let mut alloc = allocator(n);
+        let entities: Vec<_> = (0..n).map(|_| alloc.allocate().ok().unwrap()).collect();
+        let mut array: LazyGenerationalIndexArray<u8> = LazyGenerationalIndexArray::new(n);
+        assert_eq!(array.allocated_chunks(), 0);
+        let e = entities.iter().find(|e| e.index as usize >= LAZY_CHUNK_SIZE).unwrap();
+        assert!(matches!(array.get(e, &alloc), Err(GenerationalIndexError::NotSet)));
+        array.set(e, &alloc, 5).unwrap();
+        assert_eq!(array.allocated_chunks(), 1);
+        assert_eq!(*array.get(e, &alloc).unwrap(), 5);
+        // Other slots in the same chunk are there (as defaults), the other chunk still isn't.
+        let same_chunk = entities.iter().find(|o| o.index as usize >= LAZY_CHUNK_SIZE && *o != e).unwrap();
+        assert_eq!(*array.get(same_chunk, &alloc).unwrap(), 0);
+        let other_chunk = entities.iter().find(|o| (o.index as usize) < LAZY_CHUNK_SIZE).unwrap();
+        assert!(matches!(array.get(other_chunk, &alloc), Err(GenerationalIndexError::NotSet)));
+    }
+
+    #[test]
+    fn switching_free_strategy_changes_which_index_comes_next() {
+        let mut alloc = allocator(4);
+        for _ in 0..4 {
+            alloc.allocate().ok().unwrap();
+        }
+        let free = |alloc: &mut GenerationalIndexAllocator, indices: &[usize]| {
+            for &i in indices {
+                let e = alloc.iter_live_by_index().find(|e| e.index as usize == i).unwrap();
+                alloc.deallocate(&e).unwrap();
+            }
+        };
+        free(&mut alloc, &[2, 0, 3]);
+        // `Stack` reuses the most recently freed index.
+        assert_eq!(alloc.allocate().ok().unwrap().index, 3);
+        free(&mut alloc, &[3]);
+        alloc.set_free_strategy(FreeStrategy::LowestIndexFirst);
+        let next: Vec<_> = (0..3).map(|_| alloc.allocate().ok().unwrap().index).collect();
+        assert_eq!(next, [0, 2, 3]);
+    }
+
+    #[test]
+    fn iter_slots_reports_which_slots_are_live() {
+        let mut alloc = allocator(5);
+        alloc.set_free_strategy(FreeStrategy::LowestIndexFirst);
+        let entities: Vec<_> = (0..4).map(|_| alloc.allocate().ok().unwrap()).collect();
+        alloc.deallocate(&entities[1]).unwrap();
+        alloc.deallocate(&entities[3]).unwrap();
+        let slots: Vec<_> = alloc.iter_slots().collect();
+        assert_eq!(slots, [(0, true), (1, false), (2, true), (3, false), (4, false)]);
+    }
+
+    #[test]
+    fn total_counters_track_allocations_and_deallocations() {
+        let mut alloc = allocator(4);
+        let a = alloc.allocate().ok().unwrap();
+        let b = alloc.allocate().ok().unwrap();
+        alloc.deallocate(&a).unwrap();
+        // Failed calls don't count.
+        assert!(alloc.deallocate(&a).is_err());
+        alloc.allocate().ok().unwrap();
+        alloc.deallocate(&b).unwrap();
+        assert_eq!((alloc.total_allocated(), alloc.total_deallocated()), (3, 2));
+        assert_eq!((alloc.total_allocated() - alloc.total_deallocated()) as usize, alloc.live_count());
+    }
+
+    #[test]
+    fn rebuild_entities_recovers_from_a_desynced_list() {
+        let mut world = world(0, 4);
+        let spawned: Vec<_> = (0..3).map(|_| world.spawn_with_components(|_, _, _| Ok(())).ok().unwrap()).collect();
+        assert!(!world.resync_entities());
+        // Deallocated behind the world's back: one handle too many.
+        world.entity_allocator.deallocate(&spawned[0]).unwrap();
+        assert!(world.resync_entities());
+        assert!(world.entity_allocator.rebuild_entities() == world.entities);
+        assert!(world.entities.len() == 2 && !world.entities.contains(&spawned[0]));
+        // Same length, but a stale handle in place of a live one.
+        let fresh = world.entity_allocator.allocate().ok().unwrap();
+        world.entity_allocator.deallocate(&spawned[1]).unwrap();
+        assert!(world.entities.len() == world.entity_allocator.live_count());
+        assert!(world.resync_entities());
+        assert!(world.entities.contains(&fresh) && !world.entities.contains(&spawned[1]));
+    }
+
+    #[test]
+    fn iter_live_slots_yields_exactly_the_live_slots() {
+        let mut alloc = allocator(4);
+        alloc.set_free_strategy(FreeStrategy::LowestIndexFirst);
+        let mut array = GenerationalIndexArray(vec![0u32; 4]);
+        let entities: Vec<_> = (0..3).map(|_| alloc.allocate().ok().unwrap()).collect();
+        for (e, value) in entities.iter().zip([10, 11, 12]) {
+            array.set(e, &alloc, value).unwrap();
+        }
+        alloc.deallocate(&entities[1]).unwrap();
+        let slots: Vec<_> = array.iter_live_slots(&alloc).map(|(i, v)| (i, *v)).collect();
+        assert_eq!(slots, [(0, 10), (2, 12)]);
+    }
+
+    #[test]
+    fn fetch_gets_every_component_or_nothing() {
+        let mut world: World<TestComponents, ()> = World::new(0, allocator(4), TestComponents::with_capacity(4), (), vec![]);
+        let both = world.spawn_with_components(|c, e, alloc| {
+            c.small.set(e, alloc, 1)?;
+            c.big.set(e, alloc, 2)
+        }).ok().unwrap();
+        let (c, alloc) = (&mut world.components, &world.entity_allocator);
+        if let Some((small, big)) = fetch!(&both, alloc; mut c.small, c.big) {
+            *small += *big as u8;
+        }
+        assert!(matches!(fetch!(&both, alloc; c.small, c.big), Some((3, 2))));
+        // A dead handle is missing every component.
+        let dead = world.spawn_with_components(|_, _, _| Ok(())).ok().unwrap();
+        world.despawn(&dead).unwrap();
+        let (c, alloc) = (&mut world.components, &world.entity_allocator);
+        assert!(fetch!(&dead, alloc; c.small, c.big).is_none());
+        assert!(fetch!(&dead, alloc; mut c.small, c.big).is_none());
+        // So is a live one that never got its lazy component.
+        let mut world = World::new(0, allocator(4), MixedComponents::with_capacity(4), (), vec![]);
+        let partial = world.spawn_with_components(|c, e, alloc| c.common.set(e, alloc, 1)).ok().unwrap();
+        let (c, alloc) = (&mut world.components, &world.entity_allocator);
+        assert!(fetch!(&partial, alloc; c.common).is_some());
+        assert!(fetch!(&partial, alloc; mut c.common, c.rare).is_none());
+    }
+
+    #[test]
+    fn failure_on_the_second_set_frees_the_index_and_keeps_entities() {
+        let mut w = World::new(0, allocator(4), TestComponents::with_capacity(4), (), vec![]);
+        let kept = w.spawn_with_components(|c, e, alloc| c.small.set(e, alloc, 1)).ok().unwrap();
+        let stale = w.spawn().ok().unwrap();
+        w.despawn(&stale).unwrap();
+        let mut failed_index = None;
+        let spawned = w.spawn_with_components(|c, e, alloc| {
+            failed_index = Some(e.index);
+            c.small.set(e, alloc, 2)?;
+            c.big.set(&stale, alloc, 3)
+        });
+        assert!(spawned.is_err());
+        assert!(w.entities == [kept]);
+        // The index went back on the free list, so it's the next one handed out.
+        let next = w.spawn().ok().unwrap();
+        assert_eq!(Some(next.index), failed_index);
+    }
+
+    #[test]
+    fn set_capped_refuses_to_go_over_the_soft_cap() {
+        let mut alloc = allocator(4);
+        let mut array: EntityMap<Option<u8>> = GenerationalIndexArray(vec![None; 4]);
+        let entities: Vec<_> = (0..3).map(|_| alloc.allocate().ok().unwrap()).collect();
+        array.set_capped(&entities[0], &alloc, Some(1), 2).unwrap();
+        array.set_capped(&entities[1], &alloc, Some(2), 2).unwrap();
+        assert!(matches!(array.set_capped(&entities[2], &alloc, Some(3), 2), Err(GenerationalIndexError::OverSoftCap)));
+        assert_eq!(*array.get(&entities[2], &alloc).unwrap(), None);
+        assert_eq!(array.count_set(&alloc), 2);
+        // Replacing and removing still work at the cap, and removing makes room again.
+        array.set_capped(&entities[0], &alloc, Some(4), 2).unwrap();
+        array.set_capped(&entities[1], &alloc, None, 2).unwrap();
+        array.set_capped(&entities[2], &alloc, Some(3), 2).unwrap();
+        assert_eq!(array.count_set(&alloc), 2);
+    }
+
+    #[test]
+    fn crossed_reports_a_crossing_only_on_the_frame_it_happens() {
+        let mut alloc = allocator(4);
+        // Each value is (previous, current).
+        let mut array = GenerationalIndexArray(vec![(0.0f32, 0.0f32); 4]);
+        let entities: Vec<_> = (0..4).map(|_| alloc.allocate().ok().unwrap()).collect();
+        for (e, value) in entities.iter().zip([(9.0, 11.0), (11.0, 9.0), (11.0, 12.0), (9.0, 10.0)]) {
+            array.set(e, &alloc, value).unwrap();
+        }
+        let crossed: Vec<_> = array.crossed(&entities, &alloc, 10.0, |&v| v).collect();
+        // Down, up, and just reaching the threshold all count; staying on one side doesn't.
+        assert!(crossed == [entities[0], entities[1], entities[3]]);
+        // The next frame, nothing crosses again.
+        for e in &entities {
+            let v = array.get_mut(e, &alloc).unwrap();
+            v.0 = v.1;
+        }
+        assert_eq!(array.crossed(&entities, &alloc, 10.0, |&v| v).count(), 0);
+    }
+
+    #[test]
+    fn ecs_init_matches_a_hand_built_world() {
+        let (mut built, mut components, entities) = ecs_init!(TestComponents, 8);
+        let mut by_hand = allocator(8);
+        assert_eq!(built.capacity(), by_hand.capacity());
+        assert_eq!(built.live_count(), 0);
+        assert!(built.can_allocate(8) && entities.is_empty() && entities.capacity() >= 8);
+        components.for_each_array(|name, array| assert_eq!(array.capacity(), 8, "{}", name));
+        // Same free list, so the same handles come out in the same order.
+        for _ in 0..8 {
+            assert!(built.allocate().ok() == by_hand.allocate().ok());
+        }
+    }
+
+    #[test]
+    fn iter_live_by_index_only_depends_on_which_slots_are_live() {
+        // Two allocators end up with the same slots live, after freeing in different orders.
+        let mut a = allocator(6);
+        let mut b = allocator(6);
+        let from_a: Vec<_> = (0..6).map(|_| a.allocate().ok().unwrap()).collect();
+        let from_b: Vec<_> = (0..6).map(|_| b.allocate().ok().unwrap()).collect();
+        for i in [4, 1] {
+            a.deallocate(&from_a[i]).unwrap();
+        }
+        for i in [1, 4, 2] {
+            b.deallocate(&from_b[i]).unwrap();
+        }
+        b.allocate().ok().unwrap();
+        let order = |alloc: &GenerationalIndexAllocator| alloc.iter_live_by_index().map(|e| e.index).collect::<Vec<_>>();
+        assert_eq!(order(&a), order(&b));
+        let indices = order(&a);
+        assert!(indices.windows(2).all(|w| w[0] < w[1]));
+    }
+
+    #[test]
+    fn duplicated_handles_are_detected_and_resynced() {
+        let mut world = world(0, 4);
+        let a = world.spawn().ok().unwrap();
+        let b = world.spawn().ok().unwrap();
+        assert!(!has_duplicate_entities(&world.entities));
+        world.spawn().ok().unwrap();
+        // Same length and every handle alive, but `a` is in there twice and the third entity is missing.
+        world.entities = vec![a, b, a];
+        assert!(has_duplicate_entities(&world.entities));
+        // Only debug builds look for duplicates when resyncing.
+        assert_eq!(world.resync_entities(), cfg!(debug_assertions));
+        assert_eq!(has_duplicate_entities(&world.entities), !cfg!(debug_assertions));
+    }
+
+    #[test]
+    fn sorting_by_spawn_seq_gives_creation_order() {
+        let mut alloc = allocator(4);
+        let first = alloc.allocate().ok().unwrap();
+        let second = alloc.allocate().ok().unwrap();
+        alloc.deallocate(&first).unwrap();
+        // Reuses `first`'s slot, but was still created later than `second`.
+        let third = alloc.allocate().ok().unwrap();
+        let fourth = alloc.allocate().ok().unwrap();
+        let mut sorted = vec![fourth, third, second];
+        sorted.sort_by_key(|e| alloc.spawn_seq(e));
+        assert!(sorted == [second, third, fourth]);
+        assert!(alloc.spawn_seq(&first).is_none());
+    }
+
+    #[test]
+    fn any_where_stops_at_the_first_match_and_skips_dead_entities() {
+        let mut alloc = allocator(4);
+        let mut array = GenerationalIndexArray(vec![0u8; 4]);
+        let entities: Vec<_> = (0..4).map(|_| alloc.allocate().ok().unwrap()).collect();
+        for (e, value) in entities.iter().zip([1, 5, 9, 5]) {
+            array.set(e, &alloc, value).unwrap();
+        }
+        let calls = core::cell::Cell::new(0);
+        assert!(array.any_where(&entities, &alloc, |&v| {
+            calls.set(calls.get() + 1);
+            v == 5
+        }));
+        assert_eq!(calls.get(), 2);
+        alloc.deallocate(&entities[2]).unwrap();
+        assert!(!array.any_where(&entities, &alloc, |&v| v == 9));
+        assert!(!array.any_where(&[], &alloc, |_| true));
+    }
+
+    #[test]
+    fn acquire_reuses_pooled_values_and_never_hands_back_stale_data() {
+        let mut alloc = allocator(4);
+        let mut pooled: PooledGenerationalIndexArray<Vec<u8>> = PooledGenerationalIndexArray::new(4);
+        let a = alloc.allocate().ok().unwrap();
+        pooled.acquire(&a, &alloc).unwrap().extend_from_slice(&[1, 2, 3]);
+        let buffer = pooled.get(&a, &alloc).unwrap().as_ptr();
+        pooled.release(&a, &alloc).unwrap();
+        alloc.deallocate(&a).unwrap();
+        assert_eq!(pooled.pooled(), 1);
+        // The next entity gets the same buffer back, emptied.
+        let b = alloc.allocate().ok().unwrap();
+        let value = pooled.acquire(&b, &alloc).unwrap();
+        assert!(value.is_empty() && value.capacity() >= 3 && value.as_ptr() == buffer);
+        value.push(7);
+        assert_eq!(pooled.pooled(), 0);
+        // Despawned without `release`, with nothing in the pool: the slot's old contents still don't come back.
+        alloc.deallocate(&b).unwrap();
+        let c = alloc.allocate().ok().unwrap();
+        assert_eq!(c.index, b.index);
+        assert!(pooled.acquire(&c, &alloc).unwrap().is_empty());
+    }
+
+    #[test]
+    fn filled_arrays_match_the_allocator() {
+        let alloc = allocator(4);
+        let array = GenerationalIndexArray::filled(4, 7u8);
+        array.assert_parity(&alloc);
+        assert_eq!(array.0, [7; 4]);
+    }
+
+    #[test]
+    #[should_panic(expected = "component array size doesn't match the allocator's capacity")]
+    fn assert_parity_catches_a_mismatched_array() {
+        GenerationalIndexArray::filled(3, 0u8).assert_parity(&allocator(4));
+    }
+}
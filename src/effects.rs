@@ -0,0 +1,125 @@
+// Small visual effects that live in the game resources.
+
+use crate::rng::Rng;
+use crate::wasm4::{blit, blit_sub, BLIT_FLIP_X, BLIT_FLIP_Y};
+
+/// Screen shake: `set_shake` bumps the intensity, which then decays linearly to zero by `decay` each frame.
+/// While it's above zero, every `update` picks a new random draw offset of up to `intensity` pixels in each direction.
+pub struct ScreenShake {
+    intensity: f32,
+    decay: f32,
+    offset: (i32, i32),
+}
+
+impl ScreenShake {
+    pub fn new(decay: f32) -> ScreenShake {
+        ScreenShake {
+            intensity: 0.0,
+            decay,
+            offset: (0, 0),
+        }
+    }
+
+    /// Start shaking at least this hard (a weaker shake never cuts a stronger one short).
+    pub fn set_shake(&mut self, intensity: f32) {
+        self.intensity = self.intensity.max(intensity);
+    }
+
+    pub fn intensity(&self) -> f32 {
+        self.intensity
+    }
+
+    /// The offset to add to everything drawn this frame.
+    pub fn offset(&self) -> (i32, i32) {
+        self.offset
+    }
+
+    /// Call once per frame: pick this frame's offset, then decay the intensity.
+    /// The RNG is only used while shaking, so a still screen doesn't disturb the random sequence.
+    pub fn update(&mut self, rng: &mut Rng) {
+        if self.intensity <= 0.0 {
+            self.offset = (0, 0);
+            return
+        }
+        let max = self.intensity as i32;
+        let range = (max * 2 + 1) as u64;
+        let mut jitter = || (rng.next() % range) as i32 - max;
+        self.offset = (jitter(), jitter());
+        self.intensity = (self.intensity - self.decay).max(0.0);
+    }
+}
+
+// 4x4 ordered-dither (Bayer) thresholds: each step of alpha turns on the pixel that's farthest from the ones already on.
+const BAYER_4X4: [[u8; 4]; 4] = [
+    [0, 8, 2, 10],
+    [12, 4, 14, 6],
+    [3, 11, 1, 9],
+    [15, 7, 13, 5],
+];
+
+/// Whether the pixel at `(x, y)` (relative to the sprite) is drawn at this `alpha` (0 = none, 255 = all of them).
+/// Pixels turn on in 16 steps, spread evenly over each 4x4 block, so a partly faded sprite looks see-through.
+pub fn dither_keeps(x: u32, y: u32, alpha: u8) -> bool {
+    let level = (alpha as u16 + 1) >> 4;
+    (BAYER_4X4[(y % 4) as usize][(x % 4) as usize] as u16) < level
+}
+
+/// Like `blit`, but only draws the pixels `dither_keeps` at this `alpha`, to fake transparency (WASM-4 has no alpha),
+/// e.g. to fade something in. The dither pattern is fixed to the sprite, so it doesn't crawl while the sprite moves.
+/// `BLIT_FLIP_X`/`BLIT_FLIP_Y` work as usual, but `BLIT_ROTATE` isn't supported.
+pub fn blit_dithered(sprite: &[u8], x: i32, y: i32, width: u32, height: u32, flags: u32, alpha: u8) {
+    if alpha == 255 {
+        blit(sprite, x, y, width, height, flags);
+        return;
+    }
+    // Draw pixel by pixel; a 1x1 region can't be flipped, so flipping is done by mirroring where each pixel goes.
+    let pixel_flags = flags & !(BLIT_FLIP_X | BLIT_FLIP_Y);
+    for sy in 0..height {
+        for sx in 0..width {
+            if !dither_keeps(sx, sy, alpha) {
+                continue;
+            }
+            let dx = if flags & BLIT_FLIP_X != 0 { width - 1 - sx } else { sx };
+            let dy = if flags & BLIT_FLIP_Y != 0 { height - 1 - sy } else { sy };
+            blit_sub(sprite, x + dx as i32, y + dy as i32, 1, 1, sx, sy, width, pixel_flags);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shake_decays_to_zero_over_the_expected_frames() {
+        let mut rng = Rng::new();
+        let mut shake = ScreenShake::new(0.25);
+        shake.set_shake(2.0);
+        // 2.0 / 0.25 = 8 frames of shaking, each within the current intensity.
+        for frame in 0..8 {
+            assert!(shake.intensity() > 0.0, "stopped early, on frame {}", frame);
+            let max = shake.intensity() as i32;
+            shake.update(&mut rng);
+            let (ox, oy) = shake.offset();
+            assert!(ox.abs() <= max && oy.abs() <= max);
+        }
+        assert_eq!(shake.intensity(), 0.0);
+        shake.update(&mut rng);
+        assert_eq!(shake.offset(), (0, 0));
+    }
+
+    #[test]
+    fn dither_keeps_the_expected_share_of_pixels() {
+        let kept = |alpha| (0..8).flat_map(|y| (0..8).map(move |x| (x, y))).filter(|&(x, y)| dither_keeps(x, y, alpha)).count();
+        assert_eq!(kept(0), 0);
+        assert_eq!(kept(127), 32);
+        assert_eq!(kept(255), 64);
+        // One step in: just the top-left pixel of each 4x4 block.
+        assert!(dither_keeps(0, 0, 15) && dither_keeps(4, 4, 15));
+        assert_eq!(kept(15), 4);
+        // Half way: a checkerboard.
+        assert!((0..4).all(|y| (0..4).all(|x| dither_keeps(x, y, 127) == ((x + y) % 2 == 0))));
+        // Fading in never turns a pixel back off.
+        assert!((0..=255).all(|a: u8| (0..4).all(|y| (0..4).all(|x| !dither_keeps(x, y, a) || dither_keeps(x, y, a.saturating_add(1))))));
+    }
+}
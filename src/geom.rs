@@ -0,0 +1,50 @@
+// Basic 2D shapes for region queries and hit tests.
+
+/// Axis-aligned rectangle covering `min_x <= x < max_x` and `min_y <= y < max_y`.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Aabb {
+    pub min_x: f32,
+    pub min_y: f32,
+    pub max_x: f32,
+    pub max_y: f32,
+}
+
+impl Aabb {
+    /// The rectangle with its top-left corner at `(x, y)` and the given size.
+    pub fn new(x: f32, y: f32, width: f32, height: f32) -> Aabb {
+        Aabb {
+            min_x: x,
+            min_y: y,
+            max_x: x + width,
+            max_y: y + height,
+        }
+    }
+
+    pub fn contains(&self, x: f32, y: f32) -> bool {
+        x >= self.min_x && x < self.max_x && y >= self.min_y && y < self.max_y
+    }
+
+    /// Whether the two rectangles overlap (touching edges don't count).
+    pub fn intersects(&self, other: &Aabb) -> bool {
+        self.min_x < other.max_x && other.min_x < self.max_x && self.min_y < other.max_y && other.min_y < self.max_y
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn contains_includes_the_top_left_edges_only() {
+        let r = Aabb::new(10.0, 20.0, 8.0, 8.0);
+        assert!(r.contains(10.0, 20.0) && r.contains(17.9, 27.9));
+        assert!(!r.contains(18.0, 20.0) && !r.contains(10.0, 28.0));
+    }
+
+    #[test]
+    fn touching_rects_dont_intersect() {
+        let r = Aabb::new(0.0, 0.0, 8.0, 8.0);
+        assert!(r.intersects(&Aabb::new(4.0, 4.0, 8.0, 8.0)));
+        assert!(!r.intersects(&Aabb::new(8.0, 0.0, 8.0, 8.0)));
+    }
+}
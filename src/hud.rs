@@ -0,0 +1,149 @@
+// Helpers for drawing debug/HUD text and overlays.
+
+use crate::ecs::GenerationalIndex;
+use crate::effects::dither_keeps;
+use crate::wasm4::{set_pixel, text, SCREEN_SIZE};
+
+/// Draw a number with the system font, without allocating a string for it.
+pub fn text_number(n: i32, x: i32, y: i32) {
+    // Enough room for the sign and all the digits of i32::MIN.
+    let mut buf = [0u8; 11];
+    let mut i = buf.len();
+    let mut rest = n.unsigned_abs();
+    loop {
+        i -= 1;
+        buf[i] = b'0' + (rest % 10) as u8;
+        rest /= 10;
+        if rest == 0 {
+            break;
+        }
+    }
+    if n < 0 {
+        i -= 1;
+        buf[i] = b'-';
+    }
+    text(&buf[i..], x, y);
+}
+
+/// A `DRAW_COLORS` value (palette color 2, 3 or 4, never the background) that stays the same for an entity across frames,
+/// so it can be followed by eye in debug views. Different entities are spread evenly over the colors.
+pub fn debug_color(entity: &GenerationalIndex) -> u16 {
+    // Mix all the bits of the handle (splitmix64's finalizer), so neighbouring indices don't get neighbouring colors.
+    let mut h = entity.to_raw();
+    h = (h ^ (h >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    h = (h ^ (h >> 27)).wrapping_mul(0x94d049bb133111eb);
+    h ^= h >> 31;
+    2 + (h % 3) as u16
+}
+
+// The heatmap splits the screen into HEATMAP_CELLS x HEATMAP_CELLS cells.
+const HEATMAP_CELLS: usize = 16;
+const HEATMAP_CELL_SIZE: u32 = SCREEN_SIZE / HEATMAP_CELLS as u32;
+
+/// Counts how often something was at each spot on screen (e.g. every ball, every frame), to see where things cluster
+/// or settle. The grid is fixed (16x16 cells of 10 pixels), so it never allocates. Whenever a cell's count fills up,
+/// every count is halved: the proportions between cells stay about the same, and old visits slowly fade out.
+pub struct PositionHeatmap {
+    counts: [[u8; HEATMAP_CELLS]; HEATMAP_CELLS],
+}
+
+impl PositionHeatmap {
+    pub fn new() -> PositionHeatmap {
+        PositionHeatmap {
+            counts: [[0; HEATMAP_CELLS]; HEATMAP_CELLS],
+        }
+    }
+
+    /// Count one visit to the cell under screen point `(x, y)`. Off-screen points aren't counted.
+    pub fn record(&mut self, x: f32, y: f32) {
+        if x < 0.0 || y < 0.0 {
+            return;
+        }
+        let (cx, cy) = (x as usize / HEATMAP_CELL_SIZE as usize, y as usize / HEATMAP_CELL_SIZE as usize);
+        if cx >= HEATMAP_CELLS || cy >= HEATMAP_CELLS {
+            return;
+        }
+        if self.counts[cy][cx] == u8::MAX {
+            self.counts.iter_mut().flatten().for_each(|count| *count /= 2);
+        }
+        self.counts[cy][cx] += 1;
+    }
+
+    /// How many visits the cell in column `cx`, row `cy` has had.
+    pub fn count(&self, cx: usize, cy: usize) -> u8 {
+        self.counts[cy][cx]
+    }
+
+    pub fn clear(&mut self) {
+        self.counts = [[0; HEATMAP_CELLS]; HEATMAP_CELLS];
+    }
+
+    /// Shade every cell in palette color `color`, dithered by how busy it is compared to the busiest cell
+    /// (which is drawn solid). Writes straight into the framebuffer, so draw it first to keep it underneath everything.
+    pub fn draw(&self, color: u8) {
+        let max = self.counts.iter().flatten().copied().max().unwrap_or(0);
+        if max == 0 {
+            return;
+        }
+        for (cy, row) in self.counts.iter().enumerate() {
+            for (cx, &count) in row.iter().enumerate() {
+                let alpha = (count as u32 * 255 / max as u32) as u8;
+                for py in 0..HEATMAP_CELL_SIZE {
+                    for px in 0..HEATMAP_CELL_SIZE {
+                        let (x, y) = (cx as u32 * HEATMAP_CELL_SIZE + px, cy as u32 * HEATMAP_CELL_SIZE + py);
+                        if dither_keeps(x, y, alpha) {
+                            set_pixel(x as i32, y as i32, color);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Default for PositionHeatmap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn debug_color_is_stable_and_spread_over_the_colors() {
+        let mut counts = [0; 3];
+        for index in 0..300u64 {
+            let entity = GenerationalIndex::from_raw(1 << 24 | index);
+            let color = debug_color(&entity);
+            assert_eq!(color, debug_color(&GenerationalIndex::from_raw(entity.to_raw())));
+            assert!((2..=4).contains(&color));
+            counts[color as usize - 2] += 1;
+        }
+        // 100 each on average.
+        assert!(counts.iter().all(|&n| n > 70), "{:?}", counts);
+    }
+
+    #[test]
+    fn heatmap_counts_each_visit_in_its_cell() {
+        let mut heatmap = PositionHeatmap::new();
+        heatmap.record(0.0, 0.0);
+        heatmap.record(9.9, 9.9);
+        heatmap.record(10.0, 0.0);
+        heatmap.record(159.0, 25.0);
+        // Off screen.
+        heatmap.record(-1.0, 5.0);
+        heatmap.record(160.0, 5.0);
+        assert_eq!((heatmap.count(0, 0), heatmap.count(1, 0), heatmap.count(15, 2)), (2, 1, 1));
+        let total: u32 = (0..HEATMAP_CELLS).flat_map(|cy| (0..HEATMAP_CELLS).map(move |cx| (cx, cy))).map(|(cx, cy)| heatmap.count(cx, cy) as u32).sum();
+        assert_eq!(total, 4);
+        // A full cell halves everything instead of overflowing.
+        for _ in 0..253 {
+            heatmap.record(1.0, 1.0);
+        }
+        assert_eq!(heatmap.count(0, 0), 255);
+        heatmap.record(1.0, 1.0);
+        assert_eq!((heatmap.count(0, 0), heatmap.count(1, 0)), (128, 0));
+    }
+}
@@ -0,0 +1,131 @@
+// Input helpers that keep track of state between frames.
+
+use crate::wasm4::GAMEPADS;
+
+/// A gamepad with edge detection: besides which buttons are held, it knows which ones went down or up this frame.
+/// Call `update` once per frame with the raw gamepad byte (e.g. `*GAMEPAD1`), then query it as often as you like.
+#[derive(Default, Clone, Copy)]
+pub struct Gamepad {
+    current: u8,
+    previous: u8,
+}
+
+impl Gamepad {
+    pub fn update(&mut self, raw: u8) {
+        self.previous = self.current;
+        self.current = raw;
+    }
+
+    /// The raw button bits for this frame.
+    pub fn raw(&self) -> u8 {
+        self.current
+    }
+
+    /// Whether all of `buttons` are held down this frame.
+    pub fn held(&self, buttons: u8) -> bool {
+        self.current & buttons == buttons
+    }
+
+    /// Whether any of `buttons` went down this frame (it was up last frame).
+    pub fn pressed(&self, buttons: u8) -> bool {
+        self.current & !self.previous & buttons != 0
+    }
+
+    /// Whether any of `buttons` went up this frame (it was held last frame).
+    pub fn released(&self, buttons: u8) -> bool {
+        !self.current & self.previous & buttons != 0
+    }
+}
+
+/// The mouse, with the same edge detection as `Gamepad` for its buttons. Call `update` once per frame.
+#[derive(Default, Clone, Copy)]
+pub struct Mouse {
+    x: i16,
+    y: i16,
+    buttons: Gamepad,
+}
+
+impl Mouse {
+    pub fn update(&mut self, x: i16, y: i16, buttons: u8) {
+        self.x = x;
+        self.y = y;
+        self.buttons.update(buttons);
+    }
+
+    /// Cursor position in screen pixels.
+    pub fn position(&self) -> (i16, i16) {
+        (self.x, self.y)
+    }
+
+    /// Whether all of `buttons` (e.g. `MOUSE_LEFT`) are held down this frame.
+    pub fn held(&self, buttons: u8) -> bool {
+        self.buttons.held(buttons)
+    }
+
+    /// Whether any of `buttons` was clicked this frame.
+    pub fn pressed(&self, buttons: u8) -> bool {
+        self.buttons.pressed(buttons)
+    }
+
+    /// Whether any of `buttons` was let go this frame.
+    pub fn released(&self, buttons: u8) -> bool {
+        self.buttons.released(buttons)
+    }
+}
+
+/// How many players WASM-4 has gamepads for.
+pub const MAX_PLAYERS: usize = 4;
+
+/// Every player's gamepad (players 0-3 read `GAMEPAD1`-`GAMEPAD4`), each with `Gamepad`'s edge detection, so systems can
+/// handle all players the same way. Call `update` once per frame.
+#[derive(Default, Clone, Copy)]
+pub struct PlayerInput {
+    pads: [Gamepad; MAX_PLAYERS],
+}
+
+impl PlayerInput {
+    /// Read all four gamepads for this frame.
+    pub fn update(&mut self) {
+        for (pad, raw) in self.pads.iter_mut().zip(GAMEPADS) {
+            pad.update(unsafe { *raw });
+        }
+    }
+
+    /// Player `player`'s gamepad (0-3).
+    pub fn player(&self, player: usize) -> Gamepad {
+        self.pads[player]
+    }
+
+    /// Every player's gamepad, player 0 first.
+    pub fn iter(&self) -> impl Iterator<Item = &Gamepad> + '_ {
+        self.pads.iter()
+    }
+}
+
+// Reads the (mock) gamepad registers.
+#[cfg(all(test, feature = "headless"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn each_player_reads_their_own_gamepad() {
+        use crate::wasm4::{mock, BUTTON_1, BUTTON_2};
+        let _lock = mock::lock();
+        let mut players = PlayerInput::default();
+        for player in 0..MAX_PLAYERS {
+            mock::set_player_gamepad(player, 1 << player);
+        }
+        players.update();
+        for player in 0..MAX_PLAYERS {
+            assert_eq!(players.player(player).raw(), 1 << player);
+        }
+        // Edge detection works per player too.
+        mock::set_player_gamepad(2, BUTTON_1);
+        players.update();
+        mock::set_player_gamepad(2, BUTTON_1 | BUTTON_2);
+        players.update();
+        assert!(players.player(2).pressed(BUTTON_2) && !players.player(2).pressed(BUTTON_1));
+        assert!(!players.player(1).pressed(BUTTON_2));
+        assert_eq!(players.iter().count(), MAX_PLAYERS);
+    }
+}
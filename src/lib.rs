@@ -1,17 +1,40 @@
 mod alloc;
 
 mod wasm4;
-mod ecs;
+#[macro_use]
+pub mod ecs;
 mod rng;
-use ecs::{Entity, GenerationalIndexAllocator, EntityMap};
+pub mod sound;
+pub mod effects;
+pub mod spatial;
+pub mod schedule;
+pub mod hud;
+pub mod input;
+pub mod save;
+pub mod math;
+pub mod ring;
+pub mod geom;
+use ecs::{Entity, GenerationalIndexAllocator, EntityMap, World};
 use rng::Rng;
+use effects::ScreenShake;
+use spatial::SpatialHash;
+use schedule::Schedule;
+use sound::AudioMixer;
+use ring::{RingBuffer, FixedVec};
+use geom::Aabb;
+use input::{Gamepad, Mouse, PlayerInput};
 use wasm4::*;
 
-use crate::ecs::{AllocatorEntry, IndexType};
+use crate::ecs::{SpawnError, GenerationalIndex};
+pub use crate::ecs::{IndexType, GenerationType};
 
 // tune-able constant: how many entities we have.
+// Every component array reserves room for MAX_N_ENTITIES upfront, so this is the main thing eating up the heap
+// (the demo rarely has more than ~60 balls alive at once). 256 also fits in the `index-u8` feature.
 pub const INITIAL_N_ENTITIES: usize = 50;
-pub const MAX_N_ENTITIES: usize = 600;
+pub const MAX_N_ENTITIES: usize = 256;
+// Every entity needs its own index, so the index type has to be big enough (see the `index-u8` feature).
+const _: () = assert!(MAX_N_ENTITIES <= IndexType::MAX as usize + 1);
 
 pub const BALL_WIDTH: f32 = 8.0;
 pub const BALL_HEIGHT: f32 = 8.0;
@@ -19,182 +42,1818 @@ pub const BALL_HEIGHT: f32 = 8.0;
 pub const MOTION_DECAY: f32 = 7.0e-2;
 
 const AVG_SPRING_LENGTH: f32 = 15.0;
+// A link snaps once it's stretched past this many times its rest length.
+const MAX_LINK_STRETCH: f32 = 4.0;
+
+const CHARGE_STRENGTH: f32 = 0.2;
+
+// tune-able constant: how many teams new balls are split between (balls only link within their team). Balls are handed
+// out in pairs that share a team, and a ball replacing a removed one joins the team of the ball it left behind, so an
+// unlinked ball always has someone on its team to link with.
+const N_TEAMS: u32 = 2;
+
+// A free ball hitting a wall at least this hard (change in velocity, in pixels per frame) freezes the physics
+// for HITSTOP_FRAMES frames, to sell the impact. Most bounces are well under this.
+const HITSTOP_IMPACT: f32 = 3.0;
+const HITSTOP_FRAMES: u8 = 3;
+
+// Systems collect at most this many changes (links, removals, ...) per frame without touching the heap.
+// Anything past that is left for a later frame.
+const MAX_CHANGES_PER_FRAME: usize = 32;
+
+// tune-able constant: the starting value of `GameResources::max_links_per_frame`.
+const MAX_LINKS_PER_FRAME: usize = 2;
+const _: () = assert!(MAX_LINKS_PER_FRAME <= MAX_CHANGES_PER_FRAME);
+
+// How far between the previous and current physics step to draw. Physics steps exactly once per frame,
+// so the current step is drawn in full; lower this if the physics ever runs on its own fixed timestep.
+const RENDER_ALPHA: f32 = 1.0;
 
 // Example ECS component
-struct Kinematics{
+#[derive(Default)]
+pub struct Kinematics{
     x: f32,
     y: f32,
     vx: f32,
     vy: f32,
+    // Pinned entities never move (e.g. an anchor for other balls to spring from), but other balls still see them.
+    pinned: bool,
+    // Disabled entities are skipped by the physics entirely (no forces, no moving, no bounds), but still drawn.
+    // Unlike pinning, their velocity is kept, so they carry on where they left off once enabled again.
+    disabled: bool,
+    // Where the entity was before the last physics step (see `interpolated_pos`).
+    prev_x: f32,
+    prev_y: f32,
+}
+
+impl Kinematics {
+    /// An entity at `(x, y)` moving with `(vx, vy)`, neither pinned nor disabled. It hasn't moved yet, so its previous
+    /// position is the same as its current one.
+    fn new(x: f32, y: f32, vx: f32, vy: f32) -> Kinematics {
+        Kinematics{x, y, vx, vy, pinned: false, disabled: false, prev_x: x, prev_y: y}
+    }
+
+    /// Position blended between the previous physics step (`alpha = 0`) and the current one (`alpha = 1`), for smooth rendering.
+    /// `update_kinematics_system` records the previous position right before it moves the entity.
+    fn interpolated_pos(&self, alpha: f32) -> (f32, f32) {
+        (math::lerp(self.prev_x, self.x, alpha), math::lerp(self.prev_y, self.y, alpha))
+    }
+}
+
+// What happens to a free (unlinked) ball when it reaches the edge of the screen.
+#[derive(Clone, Copy)]
+pub enum BoundsMode {
+    // Reflect off the edge, scaled by the ball's elasticity.
+    Bounce,
+    // Leave through one edge and come back in through the opposite one, keeping the velocity.
+    Wrap,
+    // Remove the ball (it gets replaced by a new one, like a linked ball leaving the screen).
+    Destroy,
+    // Let the ball sink into the edge, pushing it back with a force that grows with how far in it is
+    // (`stiffness` is the velocity added per pixel, per frame). Softer than bouncing, and plays nicer with springs.
+    SoftWall { stiffness: f32 },
+}
+
+impl Kinematics {
+    /// Keep the ball on screen according to `mode`. Each axis is handled on its own, so corners work in every mode.
+    /// Returns whether the ball should be destroyed.
+    fn apply_bounds(&mut self, mode: BoundsMode, elasticity: f32) -> bool {
+        // An elasticity above 1 would add speed on every bounce, and the balls would speed up forever.
+        let elasticity = elasticity.clamp(0.0, 1.0);
+        match mode {
+            BoundsMode::Bounce => {
+                if self.x + BALL_WIDTH >= 160.0 {
+                    self.vx *= -elasticity;
+                    self.x = 160.0 - BALL_WIDTH;
+                } else if self.x < 0.0 {
+                    self.vx *= -elasticity;
+                    self.x = 0.0;
+                }
+                if self.y + BALL_HEIGHT >= 160.0 {
+                    self.vy = self.vy.abs() * -elasticity;
+                    self.y = 160.0 - BALL_HEIGHT;
+                } else if self.y < 0.0 {
+                    self.y = 0.0;
+                    self.vy *= -elasticity;
+                }
+                false
+            },
+            BoundsMode::Wrap => {
+                self.x = self.x.rem_euclid(160.0);
+                self.y = self.y.rem_euclid(160.0);
+                false
+            },
+            BoundsMode::SoftWall { stiffness } => {
+                if self.x < 0.0 {
+                    self.vx -= self.x * stiffness;
+                } else if self.x + BALL_WIDTH > 160.0 {
+                    self.vx -= (self.x + BALL_WIDTH - 160.0) * stiffness;
+                }
+                if self.y < 0.0 {
+                    self.vy -= self.y * stiffness;
+                } else if self.y + BALL_HEIGHT > 160.0 {
+                    self.vy -= (self.y + BALL_HEIGHT - 160.0) * stiffness;
+                }
+                false
+            },
+            BoundsMode::Destroy => self.x < 0.0 || self.x + BALL_WIDTH >= 160.0 || self.y < 0.0 || self.y + BALL_HEIGHT >= 160.0,
+        }
+    }
+}
+
+/// The unit vector pointing from `b` to `a`, i.e. the direction to push `a` to separate two touching balls. Balls on exactly
+/// the same spot have no direction between them, so they get `(1.0, 0.0)` instead, which still pushes them apart.
+fn collision_normal(a: &Kinematics, b: &Kinematics) -> (f32, f32) {
+    let del_x = a.x - b.x;
+    let del_y = a.y - b.y;
+    let dist = (del_x.powi(2) + del_y.powi(2)).sqrt();
+    if dist > 0.0 {
+        (del_x / dist, del_y / dist)
+    } else {
+        (1.0, 0.0)
+    }
+}
+
+/// Physics diagnostic: the sum of `0.5 * (vx² + vy²)` over every live entity (every ball counts as mass 1).
+/// If this keeps climbing with no input, something (e.g. an elasticity above 1) is adding energy.
+pub fn total_kinetic_energy(kinematics: &EntityMap<Kinematics>, entities: &[Entity], alloc: &GenerationalIndexAllocator) -> f32 {
+    entities.iter()
+        .filter_map(|e| kinematics.get(e, alloc).ok())
+        .map(|k| 0.5 * (k.vx.powi(2) + k.vy.powi(2)))
+        .sum()
+}
+
+/// Replace the contents of `out` with the position of every live entity in `entities` that has kinematics, in that order,
+/// as `x, y` pairs (`[x0, y0, x1, y1, ...]`), e.g. to hand the whole state to something outside the ECS in one go.
+/// Reusing the same `out` each time saves reallocating it.
+pub fn export_positions(kinematics: &EntityMap<Kinematics>, entities: &[Entity], alloc: &GenerationalIndexAllocator, out: &mut Vec<f32>) {
+    out.clear();
+    for k in entities.iter().filter_map(|e| kinematics.get(e, alloc).ok()) {
+        out.push(k.x);
+        out.push(k.y);
+    }
+}
+
+/// Whether the simulation has come to rest: the total kinetic energy is below `threshold` (e.g. to move a puzzle on to
+/// its next phase once the balls stop). No entities at all counts as settled.
+pub fn is_settled(kinematics: &EntityMap<Kinematics>, entities: &[Entity], alloc: &GenerationalIndexAllocator, threshold: f32) -> bool {
+    entities.is_empty() || total_kinetic_energy(kinematics, entities, alloc) < threshold
+}
+
+impl Kinematics {
+    /// Change the velocity instantly (a kick), instead of gradually like a force.
+    fn apply_impulse(&mut self, ix: f32, iy: f32) {
+        self.vx += ix;
+        self.vy += iy;
+    }
+}
+
+/// The ball under the screen point `(px, py)`: the live entity whose `BALL_WIDTH` x `BALL_HEIGHT` rect (where it's drawn)
+/// contains the point. Where balls overlap, the one drawn last (on top) wins, since that's the one the player sees.
+fn pick_entity_at(kinematics: &EntityMap<Kinematics>, alloc: &GenerationalIndexAllocator, px: f32, py: f32) -> Option<Entity> {
+    // Balls are drawn by increasing index, so search from the back.
+    alloc.iter_live_by_index().rev().find(|e| match kinematics.get(e, alloc) {
+        Ok(k) => {
+            let (x, y) = k.interpolated_pos(RENDER_ALPHA);
+            Aabb::new(x, y, BALL_WIDTH, BALL_HEIGHT).contains(px, py)
+        },
+        Err(_) => false,
+    })
+}
+
+impl spatial::Position for Kinematics {
+    fn position(&self) -> (f32, f32) {
+        (self.x, self.y)
+    }
+}
+
+// Another example component in the ECS
+struct PhysicsComponent {
+    // Fraction of speed kept when bouncing off an edge. Values outside [0, 1] are clamped when bouncing.
+    collision_elasticity: f32,
+    // Fraction of velocity lost every frame (0 = no energy loss).
+    damping: f32,
+}
+
+impl Default for PhysicsComponent {
+    fn default() -> Self {
+        PhysicsComponent{collision_elasticity: 1.0, damping: MOTION_DECAY}
+    }
+}
+
+enum BallLink {
+    ReadyToLink,
+    CurrentlyLinked(Entity)
+}
+
+// Another example component. Each ball can have a link to another ball (or be ready to link).
+pub struct SmileyBallComponent {
+    link: BallLink,
+    spring_length: f32,
+    // countdown_msec: u32,
+}
+
+impl Default for SmileyBallComponent {
+    fn default() -> Self {
+        SmileyBallComponent{link: BallLink::ReadyToLink, spring_length: AVG_SPRING_LENGTH}
+    }
+}
+
+impl SmileyBallComponent {
+    /// The balls this one is linked to (at most one for now).
+    fn links(&self) -> impl Iterator<Item = Entity> {
+        match self.link {
+            BallLink::CurrentlyLinked(other) => Some(other),
+            BallLink::ReadyToLink => None,
+        }.into_iter()
+    }
+}
+
+/// Counts of linked and unlinked balls, for balancing and the debug HUD.
+pub struct LinkStats {
+    pub linked: usize,
+    pub unlinked: usize,
+    /// Average rest length of the springs on linked balls (0 if none are linked).
+    pub avg_spring_length: f32,
+}
+
+pub fn link_stats(smileys: &EntityMap<SmileyBallComponent>, entities: &[Entity], alloc: &GenerationalIndexAllocator) -> LinkStats {
+    let mut stats = LinkStats{linked: 0, unlinked: 0, avg_spring_length: 0.0};
+    let mut total_spring_length = 0.0;
+    for sm in entities.iter().filter_map(|e| smileys.get(e, alloc).ok()) {
+        match sm.link {
+            BallLink::CurrentlyLinked(_) => {
+                stats.linked += 1;
+                total_spring_length += sm.spring_length;
+            },
+            BallLink::ReadyToLink => stats.unlinked += 1,
+        }
+    }
+    if stats.linked > 0 {
+        stats.avg_spring_length = total_spring_length / stats.linked as f32;
+    }
+    stats
+}
+
+/// Whether `a` and `b` are both live balls linked to each other. Links are always meant to be symmetric, so a one-sided
+/// link (or a link to a dead ball) counts as not linked.
+pub fn are_linked(a: Entity, b: Entity, smileys: &EntityMap<SmileyBallComponent>, alloc: &GenerationalIndexAllocator) -> bool {
+    match (smileys.get(&a, alloc), smileys.get(&b, alloc)) {
+        (Ok(sa), Ok(sb)) => sa.links().any(|e| e == b) && sb.links().any(|e| e == a),
+        _ => false,
+    }
+}
+
+/// Despawn a ball together with the ball it's linked to (resetting their components first), e.g. to pop a pair.
+/// The partner only goes too if it's alive and links back to `a`, so no other ball is left holding a link to a dead one.
+/// Returns how many balls were despawned (0 if `a` isn't a live ball).
+pub fn despawn_pair(a: Entity, gs: &mut ECS) -> usize {
+    let partner = match gs.components.raining_smiley.get(&a, &gs.entity_allocator) {
+        Ok(sm) => sm.links().find(|&b| are_linked(a, b, &gs.components.raining_smiley, &gs.entity_allocator)),
+        Err(_) => return 0,
+    };
+    let mut despawned = 0;
+    for e in core::iter::once(a).chain(partner) {
+        let _ = gs.components.reset_entity(&e, &gs.entity_allocator);
+        if gs.despawn(&e).is_ok() {
+            despawned += 1;
+        }
+    }
+    despawned
+}
+
+/// Every live ball reachable from `start` by following links (including `start` itself, first), in breadth-first order.
+/// Each ball is visited once, so cycles are fine. Empty if `start` isn't a live ball.
+pub fn link_chain(start: Entity, smileys: &EntityMap<SmileyBallComponent>, alloc: &GenerationalIndexAllocator) -> Vec<Entity> {
+    let mut chain = vec![];
+    if smileys.get(&start, alloc).is_err() {
+        return chain;
+    }
+    chain.push(start);
+    // `chain` doubles as the queue: everything after `next` still needs its links followed.
+    let mut next = 0;
+    while next < chain.len() {
+        if let Ok(sm) = smileys.get(&chain[next], alloc) {
+            for other in sm.links() {
+                if smileys.get(&other, alloc).is_ok() && !chain.contains(&other) {
+                    chain.push(other);
+                }
+            }
+        }
+        next += 1;
+    }
+    chain
+}
+
+/// The average position of every ball in `start`'s link chain (see `link_chain`), e.g. for a camera to follow a cluster.
+/// Balls in the chain without kinematics are left out. `None` if `start` isn't a live ball or nothing in the chain has a position.
+pub fn chain_centroid(start: Entity, kinematics: &EntityMap<Kinematics>, smileys: &EntityMap<SmileyBallComponent>, alloc: &GenerationalIndexAllocator) -> Option<(f32, f32)> {
+    let (mut sum_x, mut sum_y, mut n) = (0.0, 0.0, 0);
+    for e in link_chain(start, smileys, alloc) {
+        if let Ok(k) = kinematics.get(&e, alloc) {
+            sum_x += k.x;
+            sum_y += k.y;
+            n += 1;
+        }
+    }
+    if n == 0 {
+        return None;
+    }
+    Some((sum_x / n as f32, sum_y / n as f32))
+}
+
+// Example component for attraction/repulsion between balls, like electric charges.
+// Opposite signs attract, equal signs repel, and a sign of 0 is neutral.
+#[derive(Default)]
+struct ChargeComponent {
+    sign: i8,
+    strength: f32,
+}
+
+// How many recent positions a trail remembers.
+const TRAIL_LEN: usize = 4;
+
+// Example fixed-size component: the last few positions of a ball, kept in a ring buffer so it never grows.
+// Positions are stored as whole pixels, which is all the line drawing needs.
+#[derive(Default)]
+struct TrailComponent {
+    points: [(i16, i16); TRAIL_LEN],
+    // Where the next position goes (once the buffer is full, this is the oldest one, which gets overwritten).
+    next: u8,
+    len: u8,
+}
+
+impl TrailComponent {
+    fn push(&mut self, x: f32, y: f32) {
+        self.points[self.next as usize] = (x as i16, y as i16);
+        self.next = ((self.next as usize + 1) % TRAIL_LEN) as u8;
+        self.len = (self.len + 1).min(TRAIL_LEN as u8);
+    }
+
+    /// The remembered positions, newest first.
+    fn iter(&self) -> impl Iterator<Item = (i16, i16)> + '_ {
+        (1..=self.len as usize).map(move |i| self.points[(self.next as usize + TRAIL_LEN - i) % TRAIL_LEN])
+    }
+}
+
+// Example debug component: a short name drawn next to the entity (with the `debug-draw` feature).
+// It's a fixed-size byte array rather than a `String`, so labels never touch the heap.
+#[derive(Default)]
+pub struct LabelComponent([u8; 4]);
+
+impl LabelComponent {
+    /// Make a label from the first 4 bytes of `name`.
+    pub fn new(name: &[u8]) -> LabelComponent {
+        let mut label = [0; 4];
+        for (dst, src) in label.iter_mut().zip(name) {
+            *dst = *src;
+        }
+        LabelComponent(label)
+    }
+
+    /// Make a label like "b12": `prefix` followed by the last 3 digits of `n`.
+    pub fn numbered(prefix: u8, n: u32) -> LabelComponent {
+        let mut name = [prefix, 0, 0, 0];
+        let mut len = 1;
+        let n = n % 1000;
+        for place in [100, 10, 1] {
+            if n >= place || place == 1 {
+                name[len] = b'0' + (n / place % 10) as u8;
+                len += 1;
+            }
+        }
+        LabelComponent::new(&name[..len])
+    }
+
+    /// The label's bytes, without the unused padding at the end.
+    pub fn as_bytes(&self) -> &[u8] {
+        let len = self.0.iter().position(|&b| b == 0).unwrap_or(self.0.len());
+        &self.0[..len]
+    }
+}
+
+// Example optional component: which team a ball is on. Balls on different teams never link, and a ball without a team
+// (`None`, e.g. one spawned with `spawn_ball_at`) can link with anyone.
+#[derive(Default, Clone, Copy, PartialEq)]
+struct TeamComponent {
+    team: u8,
+}
+
+/// Whether two balls are allowed to link, going by their teams (see `TeamComponent`).
+fn same_team(a: Option<TeamComponent>, b: Option<TeamComponent>) -> bool {
+    match (a, b) {
+        (Some(a), Some(b)) => a == b,
+        _ => true,
+    }
+}
+
+// List your components in this struct (largest first, since their memory is reserved in this order). Each entity has one of each (each entry is optional).
+declare_components! {
+    pub struct EntityComponents {
+        kinematics: EntityMap<Kinematics>,
+        physics: EntityMap<PhysicsComponent>,
+        raining_smiley: EntityMap<SmileyBallComponent>,
+        charge: EntityMap<ChargeComponent>,
+        trail: EntityMap<TrailComponent>,
+        label: EntityMap<LabelComponent>,
+        team: EntityMap<Option<TeamComponent>>,
+    }
+}
+
+// Physics constants that can be tweaked while the game is running, with the tuning menu (hold button 2).
+struct TuningResource {
+    gravity: f32,
+    spring_stiffness: f32,
+    link_radius: f32,
+    // Cap on a single spring's pull (or push) per frame, so a badly stretched link can't fling balls across the screen.
+    max_spring_force: f32,
+    // Whether the menu is showing, and which parameter it has selected.
+    open: bool,
+    selected: usize,
+}
+
+impl TuningResource {
+    // Name, how much one left/right press changes it, and how much to scale it by for display (the HUD only draws integers).
+    const PARAMS: [(&'static str, f32, f32); 4] = [
+        ("gravity", 0.005, 1000.0),
+        ("spring", 0.002, 1000.0),
+        ("link r", 1.0, 1.0),
+        ("max pull", 0.05, 100.0),
+    ];
+
+    fn new() -> TuningResource {
+        TuningResource {
+            gravity: 0.03,
+            spring_stiffness: 0.01,
+            link_radius: 10.0,
+            max_spring_force: 0.5,
+            open: false,
+            selected: 0,
+        }
+    }
+
+    fn value(&self, param: usize) -> f32 {
+        match param {
+            0 => self.gravity,
+            1 => self.spring_stiffness,
+            2 => self.link_radius,
+            _ => self.max_spring_force,
+        }
+    }
+
+    fn value_mut(&mut self, param: usize) -> &mut f32 {
+        match param {
+            0 => &mut self.gravity,
+            1 => &mut self.spring_stiffness,
+            2 => &mut self.link_radius,
+            _ => &mut self.max_spring_force,
+        }
+    }
+
+    /// Up/down picks a parameter and left/right nudges it by its step (never below zero).
+    fn handle_input(&mut self, pad: &Gamepad) {
+        let n = Self::PARAMS.len();
+        if pad.pressed(BUTTON_UP) {
+            self.selected = (self.selected + n - 1) % n;
+        }
+        if pad.pressed(BUTTON_DOWN) {
+            self.selected = (self.selected + 1) % n;
+        }
+        let step = Self::PARAMS[self.selected].1;
+        let value = self.value_mut(self.selected);
+        if pad.pressed(BUTTON_LEFT) {
+            *value = (*value - step).max(0.0);
+        }
+        if pad.pressed(BUTTON_RIGHT) {
+            *value += step;
+        }
+    }
+}
+
+// How many gravity sources ("planets") there can be at once.
+const MAX_GRAVITY_SOURCES: usize = 4;
+
+// A point that balls fall toward ("planet mode"), pulling harder the closer they are (inverse square).
+#[derive(Clone, Copy)]
+struct GravitySource {
+    x: f32,
+    y: f32,
+    strength: f32,
+}
+
+impl GravitySource {
+    // Below this distance the pull stops growing, so a ball passing through the center doesn't get flung off.
+    const MIN_DIST: f32 = 6.0;
+
+    /// The pull on something at `(x, y)`, pointing at the source.
+    fn pull(&self, x: f32, y: f32) -> (f32, f32) {
+        let del_x = self.x - x;
+        let del_y = self.y - y;
+        let dist = (del_x.powi(2) + del_y.powi(2)).sqrt();
+        if dist == 0.0 {
+            return (0.0, 0.0);
+        }
+        let mag = math::inverse_square_falloff(dist, self.strength, Self::MIN_DIST);
+        (del_x / dist * mag, del_y / dist * mag)
+    }
+}
+
+// Counters for keeping an eye on how much work the systems do.
+#[derive(Default)]
+struct PerfCounters {
+    // Pairwise distance checks done by the last linking pass.
+    pair_checks: u32,
+}
+
+// Something that happened to the balls, kept in a short log for debugging.
+enum GameEvent {
+    Linked,
+    Removed,
+}
+
+// The ball being dragged around with the mouse, if any (see `grab_system`).
+#[derive(Default)]
+struct GrabState {
+    grabbed: Option<Entity>,
+    // Whether the ball was already pinned before it was grabbed, so it goes back to that when let go.
+    was_pinned: bool,
+    // The last few cursor positions (newest first), to work out how fast to throw the ball.
+    recent: RingBuffer<(i16, i16), 4>,
+}
+
+// All other state that doesn't fit into a component goes here.
+pub struct GameResources {
+    // hello_msg: String,
+    rng: Rng,
+    gravity_overall_mult: f32,
+    current_wind: (f32, f32),
+    // Every player's gamepad. The demo is single player, so only player 0 is used.
+    players: PlayerInput,
+    mouse: Mouse,
+    grab: GrabState,
+    tuning: TuningResource,
+    // At most this many new links form each time the linking system runs, so a big pile of overlapping balls links up
+    // over a few frames instead of all at once. The rest just get picked up next time. (More than `MAX_CHANGES_PER_FRAME`
+    // is the same as `MAX_CHANGES_PER_FRAME`.)
+    max_links_per_frame: usize,
+    // What free balls do at the screen edges.
+    bounds_mode: BoundsMode,
+    // If there are any, balls fall toward these instead of straight down.
+    gravity_sources: FixedVec<GravitySource, MAX_GRAVITY_SOURCES>,
+    screen_shake: ScreenShake,
+    // All sounds go through here, so lots of them at once get turned down.
+    audio: AudioMixer,
+    spatial_hash: SpatialHash,
+    // The mutable systems, run in order every frame.
+    schedule: Schedule<ECS>,
+    // How many frames have been updated so far.
+    frame: u32,
+    // While paused, the physics systems are skipped, but input (to unpause) and drawing keep running.
+    paused: bool,
+    // How many more frames the physics stays frozen after a hard impact (hitstop).
+    hitstop_frames: u8,
+    // The starting balls still to come, while they're being ramped in.
+    startup_ramp: Option<StartupRamp>,
+    // How many balls `add_smiley_ball` has made so far (used to label them).
+    balls_spawned: u32,
+    perf: PerfCounters,
+    // Where balls have spent their time (drawn with the `debug-draw` feature).
+    heatmap: hud::PositionHeatmap,
+    // The most recent events (with the frame they happened on), newest first.
+    events: RingBuffer<(u32, GameEvent), 10>,
+}
+
+/// The parts of `GameResources` that make up the game state (as opposed to input, caches and UI), for quick-save and
+/// quick-load alongside the entity saves. Small and `Copy`, so it can be kept around cheaply.
+#[derive(Clone, Copy)]
+pub struct ResourceSnapshot {
+    rng_state: u128,
+    gravity_overall_mult: f32,
+    current_wind: (f32, f32),
+    // The tuning values, in `TuningResource::PARAMS` order.
+    tuning: [f32; TuningResource::PARAMS.len()],
+    bounds_mode: BoundsMode,
+    frame: u32,
+}
+
+impl GameResources {
+    /// Capture the game state parts of the resources (see `ResourceSnapshot`), to put back later with `restore`.
+    pub fn snapshot(&self) -> ResourceSnapshot {
+        ResourceSnapshot {
+            rng_state: self.rng.state(),
+            gravity_overall_mult: self.gravity_overall_mult,
+            current_wind: self.current_wind,
+            tuning: core::array::from_fn(|param| self.tuning.value(param)),
+            bounds_mode: self.bounds_mode,
+            frame: self.frame,
+        }
+    }
+
+    /// Put back everything `snapshot` captured, including where the random numbers were up to.
+    pub fn restore(&mut self, snapshot: &ResourceSnapshot) {
+        self.rng = Rng::from_state(snapshot.rng_state);
+        self.gravity_overall_mult = snapshot.gravity_overall_mult;
+        self.current_wind = snapshot.current_wind;
+        for (param, &value) in snapshot.tuning.iter().enumerate() {
+            *self.tuning.value_mut(param) = value;
+        }
+        self.bounds_mode = snapshot.bounds_mode;
+        self.frame = snapshot.frame;
+    }
+}
+
+// A shape to lay balls out in, for setting up levels (see `spawn_formation`). Every shape is centered on the point it's placed at.
+#[derive(Clone, Copy)]
+pub enum Formation {
+    // Rows of `cols` balls (the last row may be short), `FORMATION_GRID_SPACING` apart.
+    Grid { cols: usize },
+    // Evenly spaced around a circle, starting at the right-most point.
+    Circle { radius: f32 },
+    // A horizontal row with this much space between ball centers.
+    Line { spacing: f32 },
+}
+
+// Distance between neighbouring ball centers in a `Formation::Grid`.
+const FORMATION_GRID_SPACING: f32 = 12.0;
+
+impl Formation {
+    /// Where the center of each of `count` balls goes, for the formation centered on `center`.
+    fn positions(self, center: (f32, f32), count: usize) -> impl Iterator<Item = (f32, f32)> {
+        let (cx, cy) = center;
+        (0..count).map(move |i| match self {
+            Formation::Grid { cols } => {
+                let cols = cols.clamp(1, count.max(1));
+                let rows = count.div_ceil(cols);
+                let col = (i % cols) as f32 - (cols - 1) as f32 / 2.0;
+                let row = (i / cols) as f32 - (rows - 1) as f32 / 2.0;
+                (cx + col * FORMATION_GRID_SPACING, cy + row * FORMATION_GRID_SPACING)
+            },
+            Formation::Circle { radius } => {
+                let angle = i as f32 * core::f32::consts::TAU / count as f32;
+                (cx + radius * angle.cos(), cy + radius * angle.sin())
+            },
+            Formation::Line { spacing } => (cx + (i as f32 - (count - 1) as f32 / 2.0) * spacing, cy),
+        })
+    }
+}
+
+// How to populate the world when the game starts (see `init_ecs`).
+pub struct InitConfig {
+    // How many balls to start with.
+    pub count: usize,
+    // Balls start at random spots up to `spread / 2` away from `center` on each axis.
+    pub center: (f32, f32),
+    pub spread: f32,
+    // If set, the balls are spawned this many per frame (see `startup_ramp_system`) instead of all on the first frame,
+    // so they trickle in and the allocation work is spread out.
+    pub ramp_per_frame: Option<usize>,
+}
+
+impl Default for InitConfig {
+    fn default() -> Self {
+        InitConfig{count: INITIAL_N_ENTITIES, center: (79.0, 30.0), spread: 20.0, ramp_per_frame: Some(5)}
+    }
+}
+
+// The balls from `InitConfig` that still have to be spawned, when they're ramped in over several frames.
+struct StartupRamp {
+    remaining: usize,
+    per_frame: usize,
+    center: (f32, f32),
+    spread: f32,
+}
+
+/// Here's the global state of the game, in our ECS object!
+/// (Note: if you have, say, 2 or 3 different types of entities that are
+/// all very distinct from eachother (and thusly have different sets of common components)
+/// you may want to create multiple `World`s (each with its own id), so you don't have to have a bunch of 
+/// optional components that are just None all the time, eating up space in the heap.
+/// For a component that only a few entities have, `ecs::LazyGenerationalIndexArray` only allocates the parts in use.)
+pub type ECS = World<EntityComponents, GameResources>;
+
+// The ECS is stored in static memory here.
+static mut STATIC_ECS_DATA: Option<ECS> = None;
+
+// Bump this whenever the save layout below changes, so old saves are rejected instead of misread.
+const SAVE_VERSION: u8 = 2;
+
+/// Write every ball (its handle, kinematics, physics, smiley, charge and team) into a save. Trails and labels are left out:
+/// a loaded ball starts without either. Handles are stored raw, including the ones inside links, and `load_balls` restores
+/// the exact same handles, so links come back intact without any remapping.
+/// Balls are written by increasing slot index, so the same world always gives the same bytes (see `diff_saves`).
+/// A save is `SAVE_HEADER_LEN` bytes plus `SAVE_RECORD_LEN` (51) per ball, so the 1024-byte WASM-4 disk only fits
+/// `SAVE_MAX_BALLS` (20) of them: check the length before `diskw`, which would cut a bigger save short.
+pub fn save_balls(gs: &ECS) -> Vec<u8> {
+    let mut balls = vec![];
+    for e in gs.entity_allocator.iter_live_by_index() {
+        let c = &gs.components;
+        if let (Ok(k), Ok(p), Ok(sm), Ok(ch)) = (c.kinematics.get(&e, &gs.entity_allocator), c.physics.get(&e, &gs.entity_allocator), c.raining_smiley.get(&e, &gs.entity_allocator), c.charge.get(&e, &gs.entity_allocator)) {
+            let team = c.team.get(&e, &gs.entity_allocator).ok().copied().flatten();
+            balls.push((e, k, p, sm, ch, team));
+        }
+    }
+    let mut w = save::Writer::new();
+    w.put_u8(SAVE_VERSION);
+    w.put_u16(balls.len() as u16);
+    for (e, k, p, sm, ch, team) in balls {
+        w.put_u64(e.to_raw());
+        w.put_f32(k.x);
+        w.put_f32(k.y);
+        w.put_f32(k.vx);
+        w.put_f32(k.vy);
+        w.put_u8(k.pinned as u8 | (k.disabled as u8) << 1);
+        w.put_f32(p.collision_elasticity);
+        w.put_f32(p.damping);
+        // Generation 0 is never handed out, so a raw 0 can't be a real handle and stands for "not linked".
+        w.put_u64(match sm.link {
+            BallLink::CurrentlyLinked(other) => other.to_raw(),
+            BallLink::ReadyToLink => 0,
+        });
+        w.put_f32(sm.spring_length);
+        w.put_u8(ch.sign as u8);
+        w.put_f32(ch.strength);
+        w.put_u8(team.map_or(SAVE_NO_TEAM, |t| t.team));
+    }
+    w.0
+}
+
+/// Replace every entity with the balls from a save made by `save_balls`. If the save can't be read (or doesn't fit),
+/// nothing changes and this returns `None`.
+pub fn load_balls(gs: &mut ECS, data: &[u8]) -> Option<()> {
+    // Read everything first, so a bad save can't leave the world half loaded.
+    let mut r = save::Reader::new(data);
+    if r.get_u8()? != SAVE_VERSION {
+        return None;
+    }
+    let count = r.get_u16()? as usize;
+    if count > MAX_N_ENTITIES {
+        return None;
+    }
+    let mut balls = Vec::with_capacity(count);
+    for _ in 0..count {
+        let e = GenerationalIndex::from_raw(r.get_u64()?);
+        let (x, y, vx, vy, flags) = (r.get_f32()?, r.get_f32()?, r.get_f32()?, r.get_f32()?, r.get_u8()?);
+        let k = Kinematics{pinned: flags & 1 != 0, disabled: flags & 2 != 0, ..Kinematics::new(x, y, vx, vy)};
+        let p = PhysicsComponent{collision_elasticity: r.get_f32()?, damping: r.get_f32()?};
+        let link = match r.get_u64()? {
+            0 => BallLink::ReadyToLink,
+            raw => BallLink::CurrentlyLinked(GenerationalIndex::from_raw(raw)),
+        };
+        let sm = SmileyBallComponent{link, spring_length: r.get_f32()?};
+        let ch = ChargeComponent{sign: r.get_u8()? as i8, strength: r.get_f32()?};
+        let team = match r.get_u8()? {
+            SAVE_NO_TEAM => None,
+            team => Some(TeamComponent{team}),
+        };
+        balls.push((e, k, p, sm, ch, team));
+    }
+
+    for e in core::mem::take(&mut gs.entities) {
+        let _ = gs.entity_allocator.deallocate(&e);
+    }
+    for (e, k, p, sm, ch, team) in balls {
+        if gs.spawn_at(&e).is_err() {
+            continue
+        }
+        let c = &mut gs.components;
+        let alloc = &gs.entity_allocator;
+        let _ = c.reset_entity(&e, alloc);
+        let _ = c.kinematics.set(&e, alloc, k);
+        let _ = c.physics.set(&e, alloc, p);
+        let _ = c.raining_smiley.set(&e, alloc, sm);
+        let _ = c.charge.set(&e, alloc, ch);
+        let _ = c.team.set(&e, alloc, team);
+    }
+    // A link to a ball that didn't make it back (e.g. a hand-edited save) would never let go, so drop those.
+    for e in &gs.entities {
+        let dangling = match gs.components.raining_smiley.get(e, &gs.entity_allocator) {
+            Ok(SmileyBallComponent{link: BallLink::CurrentlyLinked(other), ..}) => !gs.is_alive(other),
+            _ => false,
+        };
+        if dangling {
+            if let Ok(sm) = gs.components.raining_smiley.get_mut(e, &gs.entity_allocator) {
+                sm.link = BallLink::ReadyToLink;
+            }
+        }
+    }
+    Some(())
+}
+
+// How `save_balls` lays out one ball: its raw handle, then each component (kinematics, physics, smiley, charge, team) in this many bytes.
+const SAVE_HANDLE_LEN: usize = 8;
+const SAVE_COMPONENT_LENS: [usize; 5] = [17, 8, 12, 5, 1];
+// `diff_saves` marks which components changed with one bit each in a byte.
+const _: () = assert!(SAVE_COMPONENT_LENS.len() <= 8);
+// The team byte of a ball that isn't on a team (never a real team, since there are fewer than this many).
+const SAVE_NO_TEAM: u8 = u8::MAX;
+const _: () = assert!(N_TEAMS <= SAVE_NO_TEAM as u32);
+const SAVE_RECORD_LEN: usize = {
+    let mut len = SAVE_HANDLE_LEN;
+    let mut i = 0;
+    while i < SAVE_COMPONENT_LENS.len() {
+        len += SAVE_COMPONENT_LENS[i];
+        i += 1;
+    }
+    len
+};
+// The version and ball count in front of the records.
+const SAVE_HEADER_LEN: usize = 3;
+// The WASM-4 disk holds this many bytes.
+const DISK_SIZE: usize = 1024;
+/// The most balls a save made by `save_balls` can hold and still fit on the disk.
+pub const SAVE_MAX_BALLS: usize = (DISK_SIZE - SAVE_HEADER_LEN) / SAVE_RECORD_LEN;
+
+/// Split a save made by `save_balls` into each ball's record (raw handle first), or `None` if it isn't one.
+fn save_records(save: &[u8]) -> Option<Vec<&[u8]>> {
+    let mut r = save::Reader::new(save);
+    if r.get_u8()? != SAVE_VERSION {
+        return None;
+    }
+    let count = r.get_u16()? as usize;
+    let records = (0..count).map(|_| r.get_bytes(SAVE_RECORD_LEN)).collect::<Option<Vec<_>>>()?;
+    r.is_empty().then_some(records)
+}
+
+fn record_handle(record: &[u8]) -> u64 {
+    u64::from_le_bytes(record[..SAVE_HANDLE_LEN].try_into().unwrap_or_default())
+}
+
+/// Encode only what changed between two saves from `save_balls` (e.g. the last frame both sides of a netplay game agreed on,
+/// and now), which is usually much smaller than `current` itself. `apply_delta` turns it back into `current`.
+/// The delta lists the handles of balls that are gone, then every new or changed ball with a bitmask of which of its
+/// components follow (all of them for new balls). `None` if either save can't be read.
+pub fn diff_saves(previous: &[u8], current: &[u8]) -> Option<Vec<u8>> {
+    let before = save_records(previous)?;
+    let after = save_records(current)?;
+    let mut w = save::Writer::new();
+    w.put_u8(SAVE_VERSION);
+
+    let removed: Vec<u64> = before.iter().map(|r| record_handle(r))
+        .filter(|&h| !after.iter().any(|r| record_handle(r) == h))
+        .collect();
+    w.put_u16(removed.len() as u16);
+    for h in removed {
+        w.put_u64(h);
+    }
+
+    let mut changes = save::Writer::new();
+    let mut changed = 0;
+    for record in &after {
+        let old = before.iter().find(|r| record_handle(r) == record_handle(record));
+        let mut mask = 0u8;
+        let mut start = SAVE_HANDLE_LEN;
+        for (i, len) in SAVE_COMPONENT_LENS.iter().enumerate() {
+            if old.is_none_or(|old| old[start..start + len] != record[start..start + len]) {
+                mask |= 1 << i;
+            }
+            start += len;
+        }
+        if mask == 0 {
+            continue;
+        }
+        changed += 1;
+        changes.put_bytes(&record[..SAVE_HANDLE_LEN]);
+        changes.put_u8(mask);
+        let mut start = SAVE_HANDLE_LEN;
+        for (i, len) in SAVE_COMPONENT_LENS.iter().enumerate() {
+            if mask & (1 << i) != 0 {
+                changes.put_bytes(&record[start..start + len]);
+            }
+            start += len;
+        }
+    }
+    w.put_u16(changed);
+    w.put_bytes(&changes.0);
+    Some(w.0)
+}
+
+/// Rebuild a save from the `previous` save and a delta made from it with `diff_saves`. The result is exactly the `current`
+/// save the delta was made from (ready for `load_balls`). `None` if the delta doesn't fit `previous` (e.g. it changes a ball
+/// that doesn't exist without giving all of its components).
+pub fn apply_delta(previous: &[u8], delta: &[u8]) -> Option<Vec<u8>> {
+    let mut records: Vec<Vec<u8>> = save_records(previous)?.into_iter().map(|r| r.to_vec()).collect();
+    let mut r = save::Reader::new(delta);
+    if r.get_u8()? != SAVE_VERSION {
+        return None;
+    }
+    for _ in 0..r.get_u16()? {
+        let h = r.get_u64()?;
+        records.retain(|rec| record_handle(rec) != h);
+    }
+    for _ in 0..r.get_u16()? {
+        let handle = r.get_bytes(SAVE_HANDLE_LEN)?;
+        let mask = r.get_u8()?;
+        let i = match records.iter().position(|rec| &rec[..SAVE_HANDLE_LEN] == handle) {
+            Some(i) => i,
+            None if mask == (1 << SAVE_COMPONENT_LENS.len()) - 1 => {
+                let mut rec = vec![0; SAVE_RECORD_LEN];
+                rec[..SAVE_HANDLE_LEN].copy_from_slice(handle);
+                records.push(rec);
+                records.len() - 1
+            },
+            None => return None,
+        };
+        let mut start = SAVE_HANDLE_LEN;
+        for (c, len) in SAVE_COMPONENT_LENS.iter().enumerate() {
+            if mask & (1 << c) != 0 {
+                records[i][start..start + len].copy_from_slice(r.get_bytes(*len)?);
+            }
+            start += len;
+        }
+    }
+    if !r.is_empty() {
+        return None;
+    }
+    // Same order as `save_balls` writes them in: by slot index, which is the low 16 bits of a raw handle.
+    records.sort_by_key(|rec| record_handle(rec) & 0xffff);
+    let mut w = save::Writer::new();
+    w.put_u8(SAVE_VERSION);
+    w.put_u16(records.len() as u16);
+    for rec in records {
+        w.put_bytes(&rec);
+    }
+    Some(w.0)
+}
+
+#[rustfmt::skip]
+const SMILEY: [u8; 8] = [
+    0b11000011,
+    0b10000001,
+    0b00100100,
+    0b00100100,
+    0b00000000,
+    0b00100100,
+    0b10011001,
+    0b11000011,
+];
+
+// The same face looking to the right (flip it horizontally to look left).
+#[rustfmt::skip]
+const SMILEY_RIGHT: [u8; 8] = [
+    0b11000011,
+    0b10000001,
+    0b00010010,
+    0b00010010,
+    0b00000000,
+    0b00010010,
+    0b10001101,
+    0b11000011,
+];
+
+// Looking up. Up and down get their own sprites, since flipping vertically would put the mouth above the eyes.
+#[rustfmt::skip]
+const SMILEY_UP: [u8; 8] = [
+    0b11000011,
+    0b10100101,
+    0b00100100,
+    0b00000000,
+    0b00100100,
+    0b00011000,
+    0b10000001,
+    0b11000011,
+];
+
+#[rustfmt::skip]
+const SMILEY_DOWN: [u8; 8] = [
+    0b11000011,
+    0b10000001,
+    0b00000000,
+    0b00100100,
+    0b00100100,
+    0b00000000,
+    0b10100101,
+    0b11011011,
+];
+
+/// Which way a smiley should look when moving with this velocity: the sprite, and the blit flags to draw it with.
+/// Slow balls look straight ahead; otherwise they look along whichever axis they're moving faster on.
+fn directional_sprite(vx: f32, vy: f32) -> (&'static [u8], u32) {
+    // Below this speed, the direction flickers around too much to be worth showing.
+    const MIN_LOOK_SPEED: f32 = 0.5;
+    if vx.powi(2) + vy.powi(2) < MIN_LOOK_SPEED.powi(2) {
+        (&SMILEY, BLIT_1BPP)
+    } else if vx.abs() >= vy.abs() {
+        (&SMILEY_RIGHT, if vx < 0.0 { BLIT_1BPP | BLIT_FLIP_X } else { BLIT_1BPP })
+    } else if vy < 0.0 {
+        (&SMILEY_UP, BLIT_1BPP)
+    } else {
+        (&SMILEY_DOWN, BLIT_1BPP)
+    }
+}
+
+/// The `DRAW_COLORS` value to draw a link with, given how long it is and how long it wants to be, so the spring
+/// tension is visible: slack links are light (color 2), links near their rest length are color 3, stretched ones dark (color 4).
+fn tension_color(current_len: f32, rest_len: f32) -> u16 {
+    // How far from the rest length (as a fraction of it) still counts as "at rest".
+    const REST_TOLERANCE: f32 = 0.25;
+    if current_len < rest_len * (1.0 - REST_TOLERANCE) {
+        0x0002
+    } else if current_len <= rest_len * (1.0 + REST_TOLERANCE) {
+        0x0003
+    } else {
+        0x0004
+    }
+}
+
+/// Drive the game without the WASM-4 runtime (see the `headless` feature and `wasm4::mock`), e.g. from host-side tests:
+/// set the input, `step`, and check on the world. Everything runs exactly as on WASM-4, including the (fixed) RNG seed.
+#[cfg(feature = "headless")]
+pub mod headless {
+    pub use crate::wasm4::mock::{set_gamepad, set_player_gamepad, set_mouse, set_netplay};
+
+    /// Run one frame, like WASM-4 calling `update`. The first step sets up the world.
+    pub fn step() {
+        crate::update();
+    }
+
+    /// Throw the world away, so the next step sets up a fresh one (like restarting the cart).
+    pub fn reset() {
+        unsafe { *core::ptr::addr_of_mut!(crate::STATIC_ECS_DATA) = None }
+    }
+
+    /// How many entities are live (0 before the first step).
+    pub fn live_entity_count() -> usize {
+        match unsafe { &*core::ptr::addr_of!(crate::STATIC_ECS_DATA) } {
+            Some(ecs) => ecs.entity_allocator.live_count(),
+            None => 0,
+        }
+    }
+}
+
+/// Adds a ball to the ECS. This isn't a "system" per-se, this is just a function that adds a ball entity.
+/// (this is analogous to a "Command" in Bevy in that it adds an entity.)
+fn add_smiley_ball(gs: &mut ECS) -> Result<Entity, SpawnError> {
+    const POS_VARIATION: f32 = 20.0;
+    let px;
+    let py;
+    unsafe {
+        if *MOUSE_BUTTONS != 0 {
+            px = *MOUSE_X;
+            py = *MOUSE_Y;
+        } else {
+            px = 79;
+            py = 30;
+        }
+
+    }
+    add_smiley_ball_near(gs, px as f32, py as f32, POS_VARIATION)
+}
+
+/// Adds a ball at a random spot up to `spread / 2` away from `(px, py)` on each axis, with random velocity and properties.
+/// If it can't be spawned, nothing is left behind: no index stays allocated and `entities` is unchanged.
+fn add_smiley_ball_near(gs: &mut ECS, px: f32, py: f32, spread: f32) -> Result<Entity, SpawnError> {
+    if gs.entity_allocator.is_full() {
+        return Err(SpawnError::OutOfMemory)
+    }
+
+    const SPEED_VARIATION: f32 = 2.0;
+    const ELASTICITY_VARIATION: f32 = 0.0;
+    const SPRING_LEGNTH_VARIATION: f32 = 10.0;
+    let x = ((gs.resources.rng.next() % 1000) as f32 / 1000.0 - 0.5) * spread + px;
+    let y = ((gs.resources.rng.next() % 1000) as f32 / 1000.0 - 0.5) * spread + py;
+    let vx = ((gs.resources.rng.next() % 1000) as f32 / 1000.0 - 0.5) * SPEED_VARIATION;
+    let vy = ((gs.resources.rng.next() % 1000) as f32 / 1000.0 - 0.5) * SPEED_VARIATION; // 5.0 - 2.5;
+    let collision_elasticity = ((gs.resources.rng.next() % 1000) as f32 / 1000.0) * ELASTICITY_VARIATION + 1.0;
+    let spring_length = AVG_SPRING_LENGTH + ((gs.resources.rng.next() % 1000) as f32 / 1000.0 - 0.5) * SPRING_LEGNTH_VARIATION;
+    let charge_sign = (gs.resources.rng.next() % 3) as i8 - 1;
+    let label_number = gs.resources.balls_spawned;
+    let team = TeamComponent{team: (label_number / 2 % N_TEAMS) as u8};
+
+    // The entity only shows up in gs.entities once all of its components are set.
+    // Resetting first makes sure no component is left over from whatever used this slot before.
+    let spawned = gs.spawn_with_components(|c, e, alloc| {
+        c.reset_entity(e, alloc)?;
+        c.kinematics.set(e, alloc, Kinematics::new(x, y, vx, vy))?;
+        c.physics.set(e, alloc, PhysicsComponent{collision_elasticity, damping: MOTION_DECAY})?;
+        c.raining_smiley.set(e, alloc, SmileyBallComponent{link: BallLink::ReadyToLink, spring_length})?;
+        c.charge.set(e, alloc, ChargeComponent{sign: charge_sign, strength: CHARGE_STRENGTH})?;
+        c.trail.set(e, alloc, TrailComponent::default())?;
+        c.label.set(e, alloc, LabelComponent::numbered(b'b', label_number))?;
+        c.team.set(e, alloc, Some(team))
+    });
+    match spawned {
+        // Only count balls that actually made it, so labels don't skip numbers.
+        Ok(_) => gs.resources.balls_spawned = gs.resources.balls_spawned.wrapping_add(1),
+        Err(SpawnError::OutOfMemory) => trace("allocate fail"),
+        Err(SpawnError::ComponentSet(_)) => trace("component set fail"),
+    }
+    spawned
+}
+
+/// Adds a ball at exactly this position and velocity, with default physics, no link and no charge.
+/// This is the deterministic counterpart to `add_smiley_ball` (e.g. for tutorials or setting up a scene by hand).
+pub fn spawn_ball_at(gs: &mut ECS, x: f32, y: f32, vx: f32, vy: f32) -> Result<Entity, SpawnError> {
+    gs.spawn_with_components(|c, e, alloc| {
+        c.reset_entity(e, alloc)?;
+        c.kinematics.set(e, alloc, Kinematics::new(x, y, vx, vy))
+    })
+}
+
+/// Lay out `count` motionless balls in `formation` around `center`, using `spawn_ball_at` (so the same call always gives the
+/// same scene). Stops at the first ball that can't be spawned, and returns how many were.
+pub fn spawn_formation(gs: &mut ECS, formation: Formation, center: (f32, f32), count: usize) -> usize {
+    let mut spawned = 0;
+    for (x, y) in formation.positions(center, count) {
+        // Positions are ball centers, but kinematics has the top-left corner.
+        if spawn_ball_at(gs, x - BALL_WIDTH / 2.0, y - BALL_HEIGHT / 2.0, 0.0, 0.0).is_err() {
+            break;
+        }
+        spawned += 1;
+    }
+    spawned
+}
+
+/// Adds a ball right next to `target` that is already linked to it (and links `target` back), for building chains on purpose.
+/// Nothing is spawned if `target` isn't live or is already linked.
+pub fn spawn_linked_to(gs: &mut ECS, target: Entity) -> Option<Entity> {
+    let (x, y) = match gs.components.raining_smiley.get(&target, &gs.entity_allocator) {
+        Ok(SmileyBallComponent{link: BallLink::ReadyToLink, ..}) => {
+            let k = gs.components.kinematics.get(&target, &gs.entity_allocator).ok()?;
+            (k.x, k.y)
+        },
+        _ => return None,
+    };
+    let spawned = gs.spawn_with_components(|c, e, alloc| {
+        c.reset_entity(e, alloc)?;
+        c.kinematics.set(e, alloc, Kinematics::new(x + AVG_SPRING_LENGTH, y, 0.0, 0.0))?;
+        c.physics.set(e, alloc, PhysicsComponent{collision_elasticity: 1.0, damping: MOTION_DECAY})?;
+        c.raining_smiley.set(e, alloc, SmileyBallComponent{link: BallLink::CurrentlyLinked(target), spring_length: AVG_SPRING_LENGTH})
+    });
+    let new_ball = spawned.ok()?;
+    // Keep the link symmetric: the target points back at the new ball.
+    if let Ok(sm) = gs.components.raining_smiley.get_mut(&target, &gs.entity_allocator) {
+        sm.link = BallLink::CurrentlyLinked(new_ball);
+    }
+    Some(new_ball)
+}
+
+/// Allocate everything the game needs upfront and add the starting balls (or set them up to ramp in, see `InitConfig`).
+/// The game calls this on its first frame with the default config; it can also build a separate world, e.g. for a test.
+pub fn init_ecs(config: InitConfig) -> ECS {
+    // Initialize / allocate entities and components.
+    // ORDER MATTERS. Memory is reserved in the order the components are declared (largest to smallest), so the layout is fit optimally.
+    let (allocator, mut components, entities) = ecs_init!(EntityComponents, MAX_N_ENTITIES);
+    // Every component array needs exactly one slot per entity index (see `GenerationalIndexArray::assert_parity`).
+    components.for_each_array(|name, array| assert!(array.capacity() == allocator.capacity(), "{} has the wrong size", name));
+
+    // Mutable systems run in this order every frame (after input, see `run_systems`). Any of them can be switched off at
+    // runtime by name.
+    let mut schedule = Schedule::new(14);
+    schedule.add("startup_ramp", startup_ramp_system);
+    schedule.add("grab", grab_system);
+    schedule.add("tuning", tuning_menu_system);
+    schedule.add("smileys", update_smileys_system);
+    schedule.add("spatial_hash", update_spatial_hash_system);
+    schedule.add("explosions", explosion_system);
+    schedule.add("separate", separate_overlapping_system);
+    schedule.add("charges", charge_force_system);
+    schedule.add("kinematics", update_kinematics_system);
+    schedule.add("trails", trail_system);
+    schedule.add("heatmap", heatmap_system);
+    schedule.add("link_smileys", link_smileys_throttled_system);
+    schedule.add("add_balls", add_balls_if_all_linked);
+    schedule.add("screen_shake", update_screen_shake_system);
+
+    // Initialization for the ECS happens here.
+    let mut gs = World::new(
+        0,
+        allocator,
+        components,
+        GameResources{
+            // hello_msg: "Hello from Rust!".to_string(),
+            rng: Rng::new(),
+            gravity_overall_mult: 2.0,
+            current_wind: (0.0, 0.0),
+            players: PlayerInput::default(),
+            mouse: Mouse::default(),
+            grab: GrabState::default(),
+            tuning: TuningResource::new(),
+            max_links_per_frame: MAX_LINKS_PER_FRAME,
+            bounds_mode: BoundsMode::Bounce,
+            gravity_sources: FixedVec::new(),
+            screen_shake: ScreenShake::new(0.25),
+            audio: AudioMixer::new(),
+            spatial_hash: SpatialHash::new(SCREEN_SIZE as f32, SCREEN_SIZE as f32, 16.0, MAX_N_ENTITIES),
+            frame: 0,
+            paused: false,
+            hitstop_frames: 0,
+            balls_spawned: 0,
+            startup_ramp: None,
+            perf: PerfCounters::default(),
+            heatmap: hud::PositionHeatmap::new(),
+            events: RingBuffer::new(),
+            schedule,
+        },
+        entities,
+    );
+
+    // Example usage on startup: allocate entities (or leave them to `startup_ramp_system`).
+    let (cx, cy) = config.center;
+    match config.ramp_per_frame {
+        Some(per_frame) => {
+            gs.resources.startup_ramp = Some(StartupRamp { remaining: config.count, per_frame: per_frame.max(1), center: config.center, spread: config.spread });
+        },
+        None => {
+            for _ in 0..config.count {
+                let _ = add_smiley_ball_near(&mut gs, cx, cy, config.spread);
+            }
+        },
+    }
+    gs
+}
+
+/// Example immutable-reference system: take in the ECS and compute something from it (e.g. rendering)
+fn draw_smileys_system(ecs: &ECS) {
+    let (ox, oy) = ecs.resources.screen_shake.offset();
+    // Everything is drawn by increasing index, so where balls overlap, the same one stays on top every frame.
+    // Trails go underneath all the balls. Newer segments are darker, so the trail fades out into the background.
+    for e in ecs.entity_allocator.iter_live_by_index() {
+        if let Ok(trail) = ecs.components.trail.get(&e, &ecs.entity_allocator) {
+            let mut newer = None;
+            for (i, (x, y)) in trail.iter().enumerate() {
+                if let Some((nx, ny)) = newer {
+                    unsafe { *DRAW_COLORS = if i == 1 { 0x0003 } else { 0x0002 } }
+                    line(nx as i32 + 4 + ox, ny as i32 + 4 + oy, x as i32 + 4 + ox, y as i32 + 4 + oy);
+                }
+                newer = Some((x, y));
+            }
+        }
+    }
+    for player in ecs.entity_allocator.iter_live_by_index() {
+        if let Ok(p1) = ecs.components.kinematics.get(&player, &ecs.entity_allocator) {
+            if let Ok(sm) = ecs.components.raining_smiley.get(&player, &ecs.entity_allocator) {
+                let (x1, y1) = p1.interpolated_pos(RENDER_ALPHA);
+                unsafe { *DRAW_COLORS = 0x0002 }
+                if let BallLink::CurrentlyLinked(id2) = sm.link {
+                    if let Some((p2, sm2)) = fetch!(&id2, &ecs.entity_allocator; ecs.components.kinematics, ecs.components.raining_smiley) {
+                        let (x2, y2) = p2.interpolated_pos(RENDER_ALPHA);
+                        let len = ((x2 - x1).powi(2) + (y2 - y1).powi(2)).sqrt();
+                        unsafe { *DRAW_COLORS = tension_color(len, (sm.spring_length + sm2.spring_length) / 2.0) }
+                        line(x1 as i32 + 4 + ox, y1 as i32 + 4 + oy, x2 as i32 + 4 + ox, y2 as i32 + 4 + oy);
+                    } 
+                }
+                // With the `debug-draw` feature, every ball gets its own stable color to tell them apart.
+                #[cfg(feature = "debug-draw")]
+                unsafe { *DRAW_COLORS = hud::debug_color(&player) }
+                let (sprite, flags) = directional_sprite(p1.vx, p1.vy);
+                // New balls fade in over their first few frames.
+                const FADE_IN_FRAMES: u32 = 15;
+                let age = ecs.entity_allocator.age(&player, ecs.resources.frame).unwrap_or(FADE_IN_FRAMES);
+                let alpha = (age.min(FADE_IN_FRAMES) * 255 / FADE_IN_FRAMES) as u8;
+                effects::blit_dithered(sprite, x1 as i32 + ox, y1 as i32 + oy, 8, 8, flags, alpha);
+            }
+        }
+    }
+}
+
+/// Example mutable-reference system: move all entities that have kinematics (except pinned and disabled ones), slowing down the ones with physics.
+fn update_kinematics_system(ecs: &mut ECS) {
+    for e in &mut ecs.entities {
+        if let Ok(pos) = ecs.components.kinematics.get_mut(&e, &ecs.entity_allocator) {
+            if let Ok(phys) = ecs.components.physics.get(&e, &ecs.entity_allocator) {
+                if !pos.disabled {
+                    pos.vx *= 1.0 - phys.damping;
+                    pos.vy *= 1.0 - phys.damping;
+                }
+            }
+        }
+    }
+    ecs.components.kinematics.map_in_place(&ecs.entities, &ecs.entity_allocator, |pos| {
+        pos.prev_x = pos.x;
+        pos.prev_y = pos.y;
+        if pos.disabled {
+            return;
+        }
+        if pos.pinned {
+            // Forces still pile up velocity on pinned entities, so throw it away instead of moving.
+            pos.vx = 0.0;
+            pos.vy = 0.0;
+        } else {
+            pos.x += pos.vx;
+            pos.y += pos.vy;
+        }
+    });
+}
+
+/// Example system for a history component: remember where every ball with a trail ended up this frame.
+fn trail_system(ecs: &mut ECS) {
+    for e in &ecs.entities {
+        if let Ok(k) = ecs.components.kinematics.get(e, &ecs.entity_allocator) {
+            if let Ok(trail) = ecs.components.trail.get_mut(e, &ecs.entity_allocator) {
+                trail.push(k.x, k.y);
+            }
+        }
+    }
+}
+
+/// Example system that feeds a resource from components: count where every ball (its center) is this frame.
+fn heatmap_system(ecs: &mut ECS) {
+    for e in &ecs.entities {
+        if let Ok(k) = ecs.components.kinematics.get(e, &ecs.entity_allocator) {
+            ecs.resources.heatmap.record(k.x + BALL_WIDTH / 2.0, k.y + BALL_HEIGHT / 2.0);
+        }
+    }
+}
+
+/// Example mutable-reference system. Adds springlike effect to linked smiley balls.
+fn update_smileys_system(ecs: &mut ECS) {
+    // Where each removed ball was in `entities`, the ball it was linked to (if any), and the team its replacement joins.
+    let mut to_rm: FixedVec<(usize, Option<Entity>, Option<TeamComponent>), MAX_CHANGES_PER_FRAME> = FixedVec::new();
+    let mut to_unlink: FixedVec<(Entity, Entity), MAX_CHANGES_PER_FRAME> = FixedVec::new();
+    for (i, e) in &mut ecs.entities.iter_mut().enumerate() {
+        let mut k2p = None;
+
+        // Check if there's an active linked ball (get its position if so).
+        if let Ok(sm) = ecs.components.raining_smiley.get(&e, &ecs.entity_allocator) {
+            if let BallLink::CurrentlyLinked(o) = sm.link {
+                if let Some((k2, sm2)) = fetch!(&o, &ecs.entity_allocator; ecs.components.kinematics, ecs.components.raining_smiley) {
+                    k2p = Some((k2.x, k2.y, sm.spring_length, sm2.spring_length, o,));
+                }
+            }
+        }
+        
+        // Update the kinematics of this ball.
+        if let Some((pos, phys)) = fetch!(&e, &ecs.entity_allocator; mut ecs.components.kinematics, ecs.components.physics) {
+            if pos.disabled {
+                continue;
+            }
+            // apply wind
+            const WIND_SCALER: f32 = 0.03;
+            pos.vx += ecs.resources.current_wind.0 * WIND_SCALER;
+            pos.vy += ecs.resources.current_wind.1 * WIND_SCALER;
+
+            if ecs.resources.gravity_sources.is_empty() {
+                pos.vy += ecs.resources.gravity_overall_mult;
+            } else {
+                // Planet mode: add up the pull of every source on the ball's center.
+                for source in ecs.resources.gravity_sources.iter() {
+                    let (fx, fy) = source.pull(pos.x + BALL_WIDTH / 2.0, pos.y + BALL_HEIGHT / 2.0);
+                    pos.vx += fx;
+                    pos.vy += fy;
+                }
+            }
+
+            match k2p {
+                Some(k2p) => {
+                    // if it's a linked ball, apply a tension force to its link.
+                    let del_x = k2p.0 - pos.x;
+                    let del_y = k2p.1 - pos.y; 
+
+                    let mag = ((del_x.powi(2) + del_y.powi(2)).sqrt() - (k2p.2 + k2p.3) / 2.0) * ecs.resources.tuning.spring_stiffness;
+                    let max_force = ecs.resources.tuning.max_spring_force;
+                    let mag = math::clamp(mag, -max_force, max_force);
+
+                    let denom = (del_x.powi(2) + del_y.powi(2)).sqrt();
+                    if denom > 0.0 {
+                        pos.vy += del_y * mag / denom * ecs.resources.gravity_overall_mult;
+                        pos.vx += del_x * mag / denom * ecs.resources.gravity_overall_mult;
+                    }
+
+                    // if the link is stretched too far, it snaps.
+                    if denom > MAX_LINK_STRETCH * (k2p.2 + k2p.3) / 2.0 {
+                        let _ = to_unlink.push((*e, k2p.4));
+                    }
+
+                    // if it's a linked ball, remove it when it hits the screen bounds.
+                    if pos.apply_bounds(BoundsMode::Destroy, phys.collision_elasticity) && !to_rm.is_full() {
+                        // The replacement has to be able to link with the ball left behind.
+                        let team = ecs.components.team.get(&k2p.4, &ecs.entity_allocator).ok().copied().flatten();
+                        if let Ok(()) = ecs.entity_allocator.deallocate(&e) {
+                            let _ = to_rm.push((i, Some(k2p.4), team));
+                        }
+                    }
+                }
+                // if it's an unlinked ball, keep it on screen (or remove it) according to the bounds mode.
+                None => {
+                    let (vx, vy) = (pos.vx, pos.vy);
+                    let destroyed = pos.apply_bounds(ecs.resources.bounds_mode, phys.collision_elasticity);
+                    let impact = ((pos.vx - vx).powi(2) + (pos.vy - vy).powi(2)).sqrt();
+                    if impact >= HITSTOP_IMPACT {
+                        ecs.resources.hitstop_frames = HITSTOP_FRAMES;
+                    }
+                    if destroyed && !to_rm.is_full() {
+                        // Its replacement takes its place on its team (where the ball it was spawned with may still be waiting).
+                        let team = ecs.components.team.get(e, &ecs.entity_allocator).ok().copied().flatten();
+                        if let Ok(()) = ecs.entity_allocator.deallocate(&e) {
+                            let _ = to_rm.push((i, None, team));
+                        }
+                    }
+                },
+            }
+        }
+    }
+    // Both ends of a snapped link go back to being ready to link.
+    for &(e1, e2) in to_unlink.iter() {
+        for end in [e1, e2] {
+            if let Ok(sm) = ecs.components.raining_smiley.get_mut(&end, &ecs.entity_allocator) {
+                sm.link = BallLink::ReadyToLink;
+            }
+        }
+    }
+    // remove ball entities when they've been deallocated successfully (and replace them with new ones!)
+    // Also, make sure the other ball that was paired changes state to "ready to link".
+    // (Popping goes from the back, so removing by position doesn't shift the ones still to go.)
+    while let Some((i, other_ball, team)) = to_rm.pop() {
+        ecs.entities.remove(i);
+        if let Some(other_ball) = other_ball {
+            if let Ok(sm) = ecs.components.raining_smiley.get_mut(&other_ball, &ecs.entity_allocator) {
+                sm.link = BallLink::ReadyToLink;
+            }
+        }
+        ecs.resources.audio.play(sound::thud());
+        ecs.resources.screen_shake.set_shake(2.0);
+        ecs.resources.events.push((ecs.resources.frame, GameEvent::Removed));
+        if let Ok(replacement) = add_smiley_ball(ecs) {
+            let _ = ecs.components.team.set(&replacement, &ecs.entity_allocator, team);
+        }
+    }
+}
+
+/// Example mutable system: If balls are touching, link them if both have no other link.
+fn link_smileys_system(ecs: &mut ECS) {
+    let link_radius = ecs.resources.tuning.link_radius;
+    let mut links: FixedVec<(Entity, Entity), MAX_CHANGES_PER_FRAME> = FixedVec::new();
+    // The spatial hash only hands us pairs that are already within the link radius.
+    for (e1, e2) in spatial::pairs_in_band(&ecs.resources.spatial_hash, &ecs.entities, &ecs.components.kinematics, &ecs.entity_allocator, 0.0, link_radius) {
+        // Keep going through the pairs even once the cap is reached, so `perf.pair_checks` still counts all of them.
+        if links.len() >= ecs.resources.max_links_per_frame {
+            continue;
+        }
+        if let Ok(rs1) = ecs.components.raining_smiley.get(&e1, &ecs.entity_allocator) {
+            if let Ok(rs2) = ecs.components.raining_smiley.get(&e2, &ecs.entity_allocator) {
+                if let BallLink::ReadyToLink = rs1.link {
+                    if let BallLink::ReadyToLink = rs2.link {
+                        let team1 = ecs.components.team.get(&e1, &ecs.entity_allocator).ok().copied().flatten();
+                        let team2 = ecs.components.team.get(&e2, &ecs.entity_allocator).ok().copied().flatten();
+                        if !same_team(team1, team2) {
+                            continue;
+                        }
+                        let already_linking = links.iter().any(|&(a, b)| a == e1 || b == e1 || a == e2 || b == e2);
+                        if !already_linking {
+                            let _ = links.push((e1, e2));
+                        }
+                    }
+                }
+            } 
+        }   
+    }
+
+    for &(e1, e2) in links.iter() {
+        if let Ok(rsm1) = ecs.components.raining_smiley.get_mut(&e1, &ecs.entity_allocator) {
+            rsm1.link = BallLink::CurrentlyLinked(e2);
+        }
+        if let Ok(rsm2) = ecs.components.raining_smiley.get_mut(&e2, &ecs.entity_allocator) {
+            rsm2.link = BallLink::CurrentlyLinked(e1);
+        }
+        ecs.resources.audio.play(sound::beep());
+        ecs.resources.events.push((ecs.resources.frame, GameEvent::Linked));
+    }
+    ecs.resources.perf.pair_checks = ecs.resources.spatial_hash.take_checks();
+    
 }
 
-// Another example component in the ECS
-struct PhysicsComponent {
-    collision_elasticity: f32
+// Example input mutable system: this stores game input for other systems to use later (via the resources struct in the ecs struct).
+fn update_input_system(ecs: &mut ECS) {
+    // How much the wind can change per frame, so it picks up and dies down gradually.
+    const WIND_RAMP: f32 = 0.1;
+    ecs.resources.players.update();
+    ecs.resources.mouse.update(unsafe { *MOUSE_X }, unsafe { *MOUSE_Y }, unsafe { *MOUSE_BUTTONS });
+    // Holding any button slows the fall (except while the buttons are driving the tuning menu).
+    ecs.resources.gravity_overall_mult = match ecs.resources.players.player(0).raw() != 0 && !ecs.resources.tuning.open {
+        true => 0.1,
+        false => ecs.resources.tuning.gravity
+    };
+    // Button 1 toggles pause (unless button 2 is already down, so the 2-then-1 debug freeze doesn't pause).
+    let pad = ecs.resources.players.player(0);
+    if pad.pressed(BUTTON_1) && !pad.held(BUTTON_2) {
+        ecs.resources.paused = !ecs.resources.paused;
+    }
+    let mut vx = 0.0;
+    let mut vy = 0.0;
+    // The arrows drive the tuning menu while it's open (so the wind dies down).
+    if !ecs.resources.tuning.open {
+        if pad.held(BUTTON_LEFT) {
+            vx -= 1.0;
+        } else if pad.held(BUTTON_RIGHT) {
+            vx += 1.0;
+        }
+        if pad.held(BUTTON_UP) {
+            vy -= 1.0;
+        } else if pad.held(BUTTON_DOWN) {
+            vy += 1.0;
+        }
+    }
+    let (wx, wy) = ecs.resources.current_wind;
+    ecs.resources.current_wind = (math::approach(wx, vx, WIND_RAMP), math::approach(wy, vy, WIND_RAMP));
 }
 
-enum BallLink {
-    ReadyToLink,
-    CurrentlyLinked(Entity)
+/// Spawn the next few starting balls, if they're being ramped in (see `InitConfig::ramp_per_frame`). Once they're all
+/// there (or the world is full), the ramp is over.
+fn startup_ramp_system(ecs: &mut ECS) {
+    let (n, center, spread) = match &mut ecs.resources.startup_ramp {
+        Some(ramp) => {
+            let n = ramp.per_frame.min(ramp.remaining);
+            ramp.remaining -= n;
+            (n, ramp.center, ramp.spread)
+        },
+        None => return,
+    };
+    for _ in 0..n {
+        if add_smiley_ball_near(ecs, center.0, center.1, spread).is_err() {
+            ecs.resources.startup_ramp = None;
+            return;
+        }
+    }
+    if ecs.resources.startup_ramp.as_ref().is_some_and(|ramp| ramp.remaining == 0) {
+        ecs.resources.startup_ramp = None;
+    }
 }
 
-// Another example component. Each ball can have a link to another ball (or be ready to link).
-struct SmileyBallComponent {
-    link: BallLink,
-    spring_length: f32,
-    // countdown_msec: u32,
+fn add_balls_if_all_linked(ecs: &mut ECS) {
+    let any_unlinked = ecs.components.raining_smiley.any_where(&ecs.entities, &ecs.entity_allocator, |sm| matches!(sm.link, BallLink::ReadyToLink));
+    // The two new balls are there to link to each other, so only add them as a pair, on the same team (the team that goes
+    // with the spawn count can differ between them, e.g. after a single replacement ball).
+    if !any_unlinked && ecs.entity_allocator.can_allocate(2) {
+        if let (Ok(first), Ok(second)) = (add_smiley_ball(ecs), add_smiley_ball(ecs)) {
+            let team = ecs.components.team.get(&first, &ecs.entity_allocator).ok().copied().flatten();
+            let _ = ecs.components.team.set(&second, &ecs.entity_allocator, team);
+        }
+    }
 }
 
-// List your components in this struct. Each entity has one of each (each entry is optional).
-struct EntityComponents {
-    kinematics: EntityMap<Kinematics>,
-    physics: EntityMap<PhysicsComponent>,
-    raining_smiley: EntityMap<SmileyBallComponent>,
+/// Spawn or despawn balls until exactly `target` of them are live (capped at `MAX_N_ENTITIES`), e.g. for dynamic
+/// difficulty. New balls come from `add_smiley_ball`, and the oldest balls (by `spawn_seq`) go first; whatever they were linked to is
+/// freed up to link again. Returns how many are live afterwards (short of `target` if spawning failed).
+pub fn maintain_population(ecs: &mut ECS, target: usize) -> usize {
+    let target = target.min(MAX_N_ENTITIES);
+    while ecs.entity_allocator.live_count() < target {
+        if add_smiley_ball(ecs).is_err() {
+            break;
+        }
+    }
+    while ecs.entity_allocator.live_count() > target {
+        let oldest = ecs.entities.iter()
+            .filter_map(|e| Some((*e, ecs.entity_allocator.spawn_seq(e)?)))
+            .min_by_key(|&(_, seq)| seq)
+            .map(|(e, _)| e);
+        let Some(oldest) = oldest else { break };
+        if let Ok(sm) = ecs.components.raining_smiley.get(&oldest, &ecs.entity_allocator) {
+            if let BallLink::CurrentlyLinked(other) = sm.link {
+                if let Ok(sm2) = ecs.components.raining_smiley.get_mut(&other, &ecs.entity_allocator) {
+                    sm2.link = BallLink::ReadyToLink;
+                }
+            }
+        }
+        if ecs.despawn(&oldest).is_err() {
+            break;
+        }
+    }
+    ecs.entity_allocator.live_count()
 }
 
-// All other state that doesn't fit into a component goes here.
-struct GameResources {
-    // hello_msg: String,
-    rng: Rng,
-    gravity_overall_mult: f32,
-    current_wind: (f32, f32),
+/// Example system that feeds a resource from components: rebuild the spatial hash from where everything is now.
+/// This runs after entities are added/removed but before velocities are integrated, so the force systems see exact
+/// positions. Later systems see balls at most one step away from the cell they were sorted into.
+fn update_spatial_hash_system(ecs: &mut ECS) {
+    ecs.resources.spatial_hash.build(&ecs.entities, &ecs.components.kinematics, &ecs.entity_allocator);
 }
 
-/// Here's the global state of the game, in our ECS object!
-/// (Note: if you have, say, 2 or 3 different types of entities that are
-/// all very distinct from eachother (and thusly have different sets of common components)
-/// you may want to create multiple ECS structs, so you don't have to have a bunch of 
-/// optional components that are just None all the time, eating up space in the heap.)
-struct ECS {
-    entity_allocator: GenerationalIndexAllocator,
-    components: EntityComponents,
-    resources: GameResources,
-    entities: Vec<Entity>,
+/// Example force system over pairs: charged balls near each other attract (opposite signs) or repel (equal signs)
+/// with a force that falls off with distance. Each ball sums up the pull of its neighbours in the spatial hash,
+/// so both balls of a pair get equal and opposite forces without collecting every pair in a list first.
+fn charge_force_system(ecs: &mut ECS) {
+    const CHARGE_RADIUS: f32 = 24.0;
+    // Below this distance the force stops growing, so balls on top of each other don't explode apart.
+    const MIN_CHARGE_DIST: f32 = 4.0;
+    for i in 0..ecs.entities.len() {
+        let e1 = ecs.entities[i];
+        let (sign1, strength1, x1, y1) = match (ecs.components.charge.get(&e1, &ecs.entity_allocator), ecs.components.kinematics.get(&e1, &ecs.entity_allocator)) {
+            (Ok(c), Ok(k)) if c.sign != 0 && !k.disabled => (c.sign, c.strength, k.x, k.y),
+            _ => continue,
+        };
+        let mut fx = 0.0;
+        let mut fy = 0.0;
+        for j in ecs.resources.spatial_hash.candidates(x1, y1, CHARGE_RADIUS) {
+            if j == i {
+                continue
+            }
+            let e2 = ecs.entities[j];
+            if let (Ok(c2), Ok(k2)) = (ecs.components.charge.get(&e2, &ecs.entity_allocator), ecs.components.kinematics.get(&e2, &ecs.entity_allocator)) {
+                let del_x = k2.x - x1;
+                let del_y = k2.y - y1;
+                let dist = (del_x.powi(2) + del_y.powi(2)).sqrt();
+                if dist > 0.0 && dist < CHARGE_RADIUS {
+                    // Positive pulls this ball toward the other one (opposite signs), negative pushes it away.
+                    let mag = -((sign1 * c2.sign) as f32) * strength1 * c2.strength / dist.max(MIN_CHARGE_DIST);
+                    fx += del_x / dist * mag;
+                    fy += del_y / dist * mag;
+                }
+            }
+        }
+        if let Ok(k) = ecs.components.kinematics.get_mut(&e1, &ecs.entity_allocator) {
+            k.vx += fx;
+            k.vy += fy;
+        }
+    }
 }
 
-// The ECS is stored in static memory here.
-static mut STATIC_ECS_DATA: Option<ECS> = None;
+/// Push apart every pair of balls closer than `radius`, along the line between them, so they end up exactly `radius` apart.
+/// Only positions change (unlike a real collision, velocities are left alone), which is enough to stop balls piling up on
+/// top of each other. Each ball moves half the overlap, unless the other one is pinned, then it moves all of it.
+/// Uses the spatial hash, so it has to run while the hash still matches `entities`.
+fn separate_overlapping(ecs: &mut ECS, radius: f32) {
+    let mut pairs: FixedVec<(Entity, Entity), MAX_CHANGES_PER_FRAME> = FixedVec::new();
+    for pair in spatial::pairs_in_band(&ecs.resources.spatial_hash, &ecs.entities, &ecs.components.kinematics, &ecs.entity_allocator, 0.0, radius) {
+        if pairs.push(pair).is_err() {
+            break;
+        }
+    }
+    // `perf.pair_checks` is only about the linking pass, so don't let these checks count toward it.
+    ecs.resources.spatial_hash.take_checks();
+    for (e1, e2) in pairs.iter() {
+        if let Ok((k1, k2)) = ecs.components.kinematics.get2_mut(e1, e2, &ecs.entity_allocator) {
+            if k1.disabled || k2.disabled || (k1.pinned && k2.pinned) {
+                continue;
+            }
+            // An earlier pair may have moved these already, so measure again.
+            let dist = ((k1.x - k2.x).powi(2) + (k1.y - k2.y).powi(2)).sqrt();
+            if dist >= radius {
+                continue;
+            }
+            let (nx, ny) = collision_normal(k1, k2);
+            let overlap = radius - dist;
+            let (share1, share2) = match (k1.pinned, k2.pinned) {
+                (true, _) => (0.0, overlap),
+                (_, true) => (overlap, 0.0),
+                _ => (overlap / 2.0, overlap / 2.0),
+            };
+            k1.x += nx * share1;
+            k1.y += ny * share1;
+            k2.x -= nx * share2;
+            k2.y -= ny * share2;
+        }
+    }
+}
 
-#[rustfmt::skip]
-const SMILEY: [u8; 8] = [
-    0b11000011,
-    0b10000001,
-    0b00100100,
-    0b00100100,
-    0b00000000,
-    0b00100100,
-    0b10011001,
-    0b11000011,
-];
+/// Example positional system: keep balls from sitting on top of each other.
+fn separate_overlapping_system(ecs: &mut ECS) {
+    separate_overlapping(ecs, BALL_WIDTH);
+}
 
-#[no_mangle]
-fn update() {
+/// Kick every ball within `radius` of `(x, y)` straight away from it, harder the closer it is (up to `strength`).
+/// Uses the spatial hash, so it has to run while the hash still matches `entities` (right after it's rebuilt).
+fn apply_explosion(ecs: &mut ECS, x: f32, y: f32, radius: f32, strength: f32) {
+    // The spatial hash has the top-left corners of the balls, so look around where a ball centered on the explosion would be.
+    let cx = x - BALL_WIDTH / 2.0;
+    let cy = y - BALL_HEIGHT / 2.0;
+    for i in ecs.resources.spatial_hash.candidates(cx, cy, radius) {
+        let e = ecs.entities[i];
+        if let Ok(k) = ecs.components.kinematics.get_mut(&e, &ecs.entity_allocator) {
+            let del_x = k.x - cx;
+            let del_y = k.y - cy;
+            let dist = (del_x.powi(2) + del_y.powi(2)).sqrt();
+            if dist > 0.0 && dist < radius && !k.disabled {
+                let push = strength * (1.0 - dist / radius);
+                k.apply_impulse(del_x / dist * push, del_y / dist * push);
+            }
+        }
+    }
+}
 
-    /// Adds a ball to the ECS. This isn't a "system" per-se, this is just a function that adds a ball entity.
-    /// (this is analogous to a "Command" in Bevy in that it adds an entity.)
-    fn add_smiley_ball(gs: &mut ECS) {
-        if gs.entities.len() >= MAX_N_ENTITIES {
-            return
-        }
-        match gs.entity_allocator.allocate() {
-            Ok(index) => {
-                let px;
-                let py;
-                unsafe {
-                    if *MOUSE_BUTTONS != 0 {
-                        px = *MOUSE_X;
-                        py = *MOUSE_Y;
-                    } else {
-                        px = 79;
-                        py = 30;
-                    }
+/// Example interaction system: right-click to set off an explosion under the cursor.
+fn explosion_system(ecs: &mut ECS) {
+    const EXPLOSION_RADIUS: f32 = 40.0;
+    const EXPLOSION_STRENGTH: f32 = 4.0;
+    if ecs.resources.mouse.pressed(MOUSE_RIGHT) {
+        let (mx, my) = ecs.resources.mouse.position();
+        apply_explosion(ecs, mx as f32, my as f32, EXPLOSION_RADIUS, EXPLOSION_STRENGTH);
+        ecs.resources.audio.play(sound::thud());
+        ecs.resources.screen_shake.set_shake(3.0);
+    }
+}
 
-                }
+/// Example interaction system: grab a ball with the left mouse button, drag it around, and let go to throw it.
+/// The grabbed ball is pinned to the cursor (so forces don't move it, but linked balls still spring toward it),
+/// and thrown with the cursor's average speed over the last few frames.
+fn grab_system(ecs: &mut ECS) {
+    let mouse = ecs.resources.mouse;
+    let (mx, my) = mouse.position();
+    let grab = &mut ecs.resources.grab;
+    // The grabbed ball can disappear under the cursor (e.g. a linked ball dragged off screen).
+    if let Some(e) = grab.grabbed {
+        if ecs.components.kinematics.get(&e, &ecs.entity_allocator).is_err() {
+            grab.grabbed = None;
+        }
+    }
+    if mouse.pressed(MOUSE_LEFT) {
+        grab.grabbed = pick_entity_at(&ecs.components.kinematics, &ecs.entity_allocator, mx as f32, my as f32);
+        grab.recent = RingBuffer::new();
+        if let Some(e) = grab.grabbed {
+            if let Ok(k) = ecs.components.kinematics.get_mut(&e, &ecs.entity_allocator) {
+                grab.was_pinned = k.pinned;
+                k.pinned = true;
+            }
+        }
+    }
+    let Some(e) = grab.grabbed else {
+        return
+    };
+    let Ok(k) = ecs.components.kinematics.get_mut(&e, &ecs.entity_allocator) else {
+        return
+    };
+    grab.recent.push((mx, my));
+    k.x = mx as f32 - BALL_WIDTH / 2.0;
+    k.y = my as f32 - BALL_HEIGHT / 2.0;
+    if mouse.released(MOUSE_LEFT) {
+        k.pinned = grab.was_pinned;
+        let newest = grab.recent.iter().next().copied();
+        let oldest = grab.recent.iter().last().copied();
+        if let (Some((x1, y1)), Some((x0, y0))) = (newest, oldest) {
+            let frames = (grab.recent.len() as f32 - 1.0).max(1.0);
+            k.vx = (x1 - x0) as f32 / frames;
+            k.vy = (y1 - y0) as f32 / frames;
+        }
+        grab.grabbed = None;
+    }
+}
 
-                const SPEED_VARIATION: f32 = 2.0;
-                const POS_VARIATION: f32 = 20.0;
-                const ELASTICITY_VARIATION: f32 = 0.0;
-                const SPRING_LEGNTH_VARIATION: f32 = 10.0;
-                let x = ((gs.resources.rng.next() % 1000) as f32 / 1000.0 - 0.5) * POS_VARIATION + px as f32;
-                let y = ((gs.resources.rng.next() % 1000) as f32 / 1000.0 - 0.5) * POS_VARIATION + py as f32;
-                let vx = ((gs.resources.rng.next() % 1000) as f32 / 1000.0 - 0.5) * SPEED_VARIATION;
-                let vy = ((gs.resources.rng.next() % 1000) as f32 / 1000.0 - 0.5) * SPEED_VARIATION; // 5.0 - 2.5;
-                let collision_elasticity = ((gs.resources.rng.next() % 1000) as f32 / 1000.0) * ELASTICITY_VARIATION + 1.0;
-                let spring_length = AVG_SPRING_LENGTH + ((gs.resources.rng.next() % 1000) as f32 / 1000.0 - 0.5) * SPRING_LEGNTH_VARIATION;
-
-                // We push this generational index in, then we can reliably set the components (gs.entities will have something in it)
-                gs.entities.push(index);
-                if let Err(_) = gs.components.kinematics.set(&gs.entities.last().unwrap(), &gs.entity_allocator, Kinematics{x , y, vx, vy}) {
-                    trace("Pos component set fail")
+/// Example resource-only system: advance the screen shake, which the draw systems read as an offset.
+fn update_screen_shake_system(ecs: &mut ECS) {
+    ecs.resources.screen_shake.update(&mut ecs.resources.rng);
+}
 
-                }
-                if let Err(_) = gs.components.physics.set(&gs.entities.last().unwrap(), &gs.entity_allocator, PhysicsComponent{collision_elasticity}) {
-                    trace("Phys component set fail")
-                }
-                if let Err(_) = gs.components.raining_smiley.set(&gs.entities.last().unwrap(), &gs.entity_allocator, SmileyBallComponent{link: BallLink::ReadyToLink, spring_length}) {
-                    trace("Phys component set fail")
-                }
-            },
-            Err(_) => {
-                trace("allocate fail");
-            },
+/// Example UI system: while button 2 is held (on its own, since both buttons freeze the physics), the arrows tune the physics.
+fn tuning_menu_system(ecs: &mut ECS) {
+    let pad = ecs.resources.players.player(0);
+    ecs.resources.tuning.open = pad.held(BUTTON_2) && !pad.held(BUTTON_1);
+    if ecs.resources.tuning.open {
+        ecs.resources.tuning.handle_input(&pad);
+    }
+}
+
+/// Example immutable system for UI: list the tunable parameters, marking the selected one.
+fn draw_tuning_menu_system(ecs: &ECS) {
+    let tuning = &ecs.resources.tuning;
+    if !tuning.open {
+        return
+    }
+    unsafe { *DRAW_COLORS = 0x0004 }
+    for (i, (name, _, display_scale)) in TuningResource::PARAMS.iter().enumerate() {
+        let y = 4 + i as i32 * 10;
+        if i == tuning.selected {
+            text(">", 4, y);
         }
+        text(name, 14, y);
+        hud::text_number((tuning.value(i) * display_scale) as i32, 86, y);
+    }
+}
+
+// Balls don't move far in a few frames, so linking doesn't need to run every frame.
+fn link_smileys_throttled_system(ecs: &mut ECS) {
+    schedule::throttle(ecs.resources.frame, 3, || link_smileys_system(ecs));
+}
+
+// Every scheduled system that changes the simulation (the balls, their links, or what's recorded about them). These are
+// all switched off together while the game is frozen, so nothing builds up (like velocity from charges) to be let loose
+// on the first frame back.
+const SIMULATION_SYSTEMS: [&str; 11] = [
+    "startup_ramp", "grab", "smileys", "explosions", "separate", "charges", "kinematics", "trails", "heatmap", "link_smileys", "add_balls",
+];
+
+/// Switch the simulation systems off while the game is paused, during a hitstop, or (a debug helper) while buttons 1 and 2
+/// are held together. Input and drawing keep running either way.
+fn freeze_physics_if_paused(ecs: &mut ECS) {
+    let held = ecs.resources.players.player(0).held(BUTTON_1 | BUTTON_2);
+    let hitstop = ecs.resources.hitstop_frames > 0;
+    ecs.resources.hitstop_frames = ecs.resources.hitstop_frames.saturating_sub(1);
+    let frozen = held || ecs.resources.paused || hitstop;
+    for name in SIMULATION_SYSTEMS {
+        ecs.resources.schedule.set_enabled(name, !frozen);
     }
+}
+
+/// Run one frame's worth of mutable systems: input first, then the schedule (see `init_ecs` for its order).
+/// Input runs on its own before the schedule is started, so a pause pressed this frame already freezes this frame's physics.
+fn run_systems(ecs: &mut ECS) {
+    update_input_system(ecs);
+    freeze_physics_if_paused(ecs);
+    // The schedule lives in the resources, so take it out while it runs the systems (which need the whole ECS).
+    let schedule = core::mem::take(&mut ecs.resources.schedule);
+    schedule.run(ecs);
+    ecs.resources.schedule = schedule;
+}
 
+#[no_mangle]
+fn update() {
     // Each update frame, load in a reference to the static ECS data.
     // The very first update will have to initialize this.
-    let mut ecs: &mut ECS;
+    let ecs: &mut ECS;
     unsafe {
         match STATIC_ECS_DATA {
             None => {
 
                 alloc::init_heap();
-
-                // Initialize / allocate entities and components.
-                // ORDER MATTERS. Reserve memory in order from largest to smallest components, so the layout is fit optimally.
-                let mut pos_comp_items = Vec::with_capacity(MAX_N_ENTITIES);
-                let mut phys_comp_items = Vec::with_capacity(MAX_N_ENTITIES);
-                let mut raining_smiley_items = Vec::with_capacity(MAX_N_ENTITIES);
-
-                let entities = Vec::with_capacity(MAX_N_ENTITIES);
-
-                let mut entries = Vec::with_capacity(MAX_N_ENTITIES);
-                let mut free = Vec::with_capacity(MAX_N_ENTITIES);
-
-                // The ECS has a max size limit. We allocate everything upfront.
-                for i in 0..MAX_N_ENTITIES as IndexType {
-                    entries.push(AllocatorEntry::new());
-                    free.push(i);
-                    pos_comp_items.push(Kinematics{x: 0.0, y: 0.0, vx: 0.0, vy: 0.0});
-                    phys_comp_items.push(PhysicsComponent{collision_elasticity: 1.0});
-                    raining_smiley_items.push(SmileyBallComponent{link: BallLink::ReadyToLink, spring_length: 0.0});
-                }
-
-                // Initialization for the ECS happens here.
-                STATIC_ECS_DATA = Some(ECS{
-                    entity_allocator: GenerationalIndexAllocator::new(entries, free),
-                    components: EntityComponents{
-                        kinematics: EntityMap{0: pos_comp_items},
-                        physics: EntityMap{0: phys_comp_items},
-                        raining_smiley: EntityMap{0: raining_smiley_items},
-                    },
-                    entities,
-                    resources: GameResources{
-                        // hello_msg: "Hello from Rust!".to_string(),
-                        rng: Rng::new(),
-                        gravity_overall_mult: 2.0,
-                        current_wind: (0.0, 0.0)
-                    }
-                });
-
-                // Example usage on startup: allocate entities.
-                // #[allow(static_mut_ref)]
-                if let Some(gs) = &mut STATIC_ECS_DATA {
-                    for _ in 0..INITIAL_N_ENTITIES {
-                        add_smiley_ball(gs);
-                    }
-                }
-
+                STATIC_ECS_DATA = Some(init_ecs(InitConfig::default()));
             },
             _ => {}
         }
@@ -212,228 +1871,972 @@ fn update() {
         }
     }
 
-    /// Example immutable-reference system: take in the ECS and compute something from it (e.g. rendering)
-    fn draw_smileys_system(ecs: &ECS) {
-        for player in &ecs.entities {
-            if let Ok(p1) = ecs.components.kinematics.get(&player, &ecs.entity_allocator) {
-                if let Ok(sm) = ecs.components.raining_smiley.get(&player, &ecs.entity_allocator) {
-                    unsafe { *DRAW_COLORS = 0x0002 }
-                    if let BallLink::CurrentlyLinked(id2) = sm.link {
-                        if let Ok(p2) = ecs.components.kinematics.get(&id2, &ecs.entity_allocator) {
-                            unsafe { *DRAW_COLORS = 0x0003 }
-                            line(p1.x as i32 + 4, p1.y as i32 + 4, p2.x as i32 + 4, p2.y as i32 + 4);
-                        } 
-                    }
-                    blit(&SMILEY, p1.x as i32, p1.y as i32, 8, 8, BLIT_1BPP);
-                }
+    unsafe { *DRAW_COLORS = 2 }
+
+    // Running the game is just playing forward all the systems!!
+
+    // The entities list is kept next to the allocator by hand; if the two ever disagree (or, in debug builds, a handle is
+    // in there twice), trust the allocator.
+    if ecs.resync_entities() {
+        trace("entities list was out of sync, rebuilt it");
+    }
+
+    // New entities get stamped with the frame they were spawned on.
+    ecs.entity_allocator.set_frame(ecs.resources.frame);
+
+    // mutable systems
+    run_systems(ecs);
+
+    // immutable systems
+    // With the `debug-draw` feature, the heatmap of where balls have been goes underneath everything.
+    #[cfg(feature = "debug-draw")]
+    ecs.resources.heatmap.draw(2);
+    draw_smileys_system(&ecs);
+    draw_tuning_menu_system(&ecs);
+    #[cfg(feature = "debug-draw")]
+    {
+        ecs.resources.spatial_hash.debug_draw();
+        // Kinetic energy in hundredths, to spot physics bugs that add energy.
+        unsafe { *DRAW_COLORS = 0x0004 }
+        text("E", 3, 140);
+        hud::text_number((total_kinetic_energy(&ecs.components.kinematics, &ecs.entities, &ecs.entity_allocator) * 100.0) as i32, 13, 140);
+        // Tracked entities vs. live ones by the allocator's counts. If these ever differ, something leaked.
+        text("N", 3, 120);
+        hud::text_number(ecs.entities.len() as i32, 13, 120);
+        text("A", 43, 120);
+        hud::text_number(ecs.entity_allocator.total_allocated().wrapping_sub(ecs.entity_allocator.total_deallocated()) as i32, 53, 120);
+        // Linked / unlinked ball counts.
+        let stats = link_stats(&ecs.components.raining_smiley, &ecs.entities, &ecs.entity_allocator);
+        text("L", 3, 130);
+        hud::text_number(stats.linked as i32, 13, 130);
+        text("U", 43, 130);
+        hud::text_number(stats.unlinked as i32, 53, 130);
+        text("S", 83, 130);
+        hud::text_number(stats.avg_spring_length as i32, 93, 130);
+        // How many distance checks linking took, to make sure the spatial hash keeps it from growing quadratically.
+        text("C", 83, 140);
+        hud::text_number(ecs.resources.perf.pair_checks as i32, 93, 140);
+        // Every ball's label, just above it.
+        let (ox, oy) = ecs.resources.screen_shake.offset();
+        for (e, label) in ecs.query(&ecs.components.label) {
+            if let Ok(k) = ecs.components.kinematics.get(&e, &ecs.entity_allocator) {
+                let (x, y) = k.interpolated_pos(RENDER_ALPHA);
+                text(label.as_bytes(), x as i32 + ox, y as i32 - 9 + oy);
             }
         }
+        // Memory map of the allocator: one 2x2 block per slot, dark for live and light for free.
+        for (i, live) in ecs.entity_allocator.iter_slots() {
+            unsafe { *DRAW_COLORS = if live { 0x0004 } else { 0x0002 } }
+            rect(124 + (i as i32 % 16) * 2, 96 + (i as i32 / 16) * 2, 2, 2);
+        }
+        unsafe { *DRAW_COLORS = 0x0004 }
+        // The event log, newest at the top, with the frame each event happened on.
+        for (i, (frame, event)) in ecs.resources.events.iter().enumerate() {
+            let y = 4 + i as i32 * 9;
+            text(match event {
+                GameEvent::Linked => "link",
+                GameEvent::Removed => "gone",
+            }, 80, y);
+            hud::text_number(*frame as i32, 120, y);
+        }
     }
 
-    /// Example mutable-reference system: move all entities that have kinematics.
-    fn update_kinematics_system(ecs: &mut ECS) {
-        for e in &mut ecs.entities {
-            if let Ok(pos) = ecs.components.kinematics.get_mut(&e, &ecs.entity_allocator) {
-                pos.x += pos.vx;
-                pos.y += pos.vy;
+    unsafe { *DRAW_COLORS = 0x0004 }
+    text("rust-wasm4-mini-ecs", 3, 150);
+    if ecs.resources.paused {
+        text("PAUSED", 56, 76);
+    }
 
-            }
+    ecs.resources.audio.end_frame();
+    ecs.resources.frame = ecs.resources.frame.wrapping_add(1);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A world with no balls in it, to add exactly the ones a test needs.
+    fn empty_world() -> ECS {
+        let mut ecs = init_ecs(InitConfig { count: 0, ramp_per_frame: None, ..InitConfig::default() });
+        // The input system sets this every frame.
+        ecs.resources.gravity_overall_mult = ecs.resources.tuning.gravity;
+        ecs
+    }
+
+    #[test]
+    fn init_ecs_spawns_the_configured_count() {
+        let ecs = init_ecs(InitConfig { count: 10, ramp_per_frame: None, ..InitConfig::default() });
+        assert_eq!(ecs.entity_allocator.live_count(), 10);
+        assert_eq!(ecs.entities.len(), 10);
+        assert_eq!(empty_world().entity_allocator.live_count(), 0);
+    }
+
+    #[test]
+    fn interpolated_pos_goes_from_the_previous_step_to_the_current_one() {
+        let mut ecs = empty_world();
+        let e = spawn_ball_at(&mut ecs, 10.0, 20.0, 3.0, -4.0).unwrap();
+        update_kinematics_system(&mut ecs);
+        // Changing the velocity after the step (e.g. an explosion) doesn't change where the ball came from.
+        let k = ecs.components.kinematics.get_mut(&e, &ecs.entity_allocator).unwrap();
+        k.apply_impulse(5.0, 5.0);
+        assert_eq!(k.interpolated_pos(0.0), (10.0, 20.0));
+        assert_eq!(k.interpolated_pos(1.0), (k.x, k.y));
+        assert_eq!(k.interpolated_pos(0.5), ((10.0 + k.x) / 2.0, (20.0 + k.y) / 2.0));
+    }
+
+    #[test]
+    fn spawn_linked_to_links_both_ends() {
+        let mut ecs = empty_world();
+        let target = spawn_ball_at(&mut ecs, 40.0, 40.0, 0.0, 0.0).unwrap();
+        let new_ball = spawn_linked_to(&mut ecs, target).unwrap();
+        let links = |e| ecs.components.raining_smiley.get(&e, &ecs.entity_allocator).unwrap().links().collect::<Vec<_>>();
+        assert!(links(target) == [new_ball]);
+        assert!(links(new_ball) == [target]);
+        // The target is taken now, so nothing else gets linked to it.
+        assert!(spawn_linked_to(&mut ecs, target).is_none());
+        assert_eq!(ecs.entities.len(), 2);
+    }
+
+    #[test]
+    fn damping_slows_down_unlinked_balls() {
+        let mut ecs = empty_world();
+        let damped = spawn_ball_at(&mut ecs, 40.0, 40.0, 2.0, 0.0).unwrap();
+        let undamped = spawn_ball_at(&mut ecs, 80.0, 80.0, 2.0, 0.0).unwrap();
+        ecs.components.physics.get_mut(&undamped, &ecs.entity_allocator).unwrap().damping = 0.0;
+        let speed = |ecs: &ECS, e| ecs.components.kinematics.get(&e, &ecs.entity_allocator).unwrap().vx;
+        let mut last = speed(&ecs, damped);
+        for _ in 0..20 {
+            update_kinematics_system(&mut ecs);
+            assert!(speed(&ecs, damped) < last);
+            last = speed(&ecs, damped);
         }
+        assert!((last - 2.0 * (1.0 - MOTION_DECAY).powi(20)).abs() < 1e-4);
+        assert_eq!(speed(&ecs, undamped), 2.0);
     }
 
-    /// Example mutable-reference system. Adds springlike effect to linked smiley balls.
-    fn update_smileys_system(ecs: &mut ECS) {
-        let mut to_rm = vec![];
-        for (i, e) in &mut ecs.entities.iter_mut().enumerate() {
-            let mut k2p = None;
+    #[test]
+    fn opposite_charges_accelerate_toward_each_other() {
+        let mut ecs = empty_world();
+        let left = spawn_ball_at(&mut ecs, 40.0, 40.0, 0.0, 0.0).unwrap();
+        let right = spawn_ball_at(&mut ecs, 50.0, 40.0, 0.0, 0.0).unwrap();
+        for (e, sign) in [(left, 1), (right, -1)] {
+            ecs.components.charge.set(&e, &ecs.entity_allocator, ChargeComponent{sign, strength: CHARGE_STRENGTH}).unwrap();
+        }
+        update_spatial_hash_system(&mut ecs);
+        charge_force_system(&mut ecs);
+        let velocity = |e| {
+            let k = ecs.components.kinematics.get(&e, &ecs.entity_allocator).unwrap();
+            (k.vx, k.vy)
+        };
+        let (lvx, lvy) = velocity(left);
+        assert!(lvx > 0.0 && lvy == 0.0);
+        assert_eq!(velocity(right), (-lvx, 0.0));
+    }
 
-            // Check if there's an active linked ball (get its position if so).
-            if let Ok(sm) = ecs.components.raining_smiley.get(&e, &ecs.entity_allocator) {
-                if let BallLink::CurrentlyLinked(o) = sm.link {
-                    if let Ok(k2) = ecs.components.kinematics.get(&o, &ecs.entity_allocator) {
-                        if let Ok(sm2) = ecs.components.raining_smiley.get(&o, &ecs.entity_allocator) {
-                            k2p = Some((k2.x, k2.y, sm.spring_length, sm2.spring_length, o,));
-                        }
-                    }
-                }
+    #[test]
+    fn trail_overwrites_the_oldest_positions() {
+        let mut trail = TrailComponent::default();
+        assert_eq!(trail.iter().count(), 0);
+        for i in 0..TRAIL_LEN as i16 + 2 {
+            trail.push(i as f32, -i as f32);
+        }
+        // Only the last TRAIL_LEN positions are left, newest first.
+        let expected: Vec<_> = (2..TRAIL_LEN as i16 + 2).rev().map(|i| (i, -i)).collect();
+        assert_eq!(trail.iter().collect::<Vec<_>>(), expected);
+    }
+
+    #[test]
+    fn wrap_mode_brings_a_ball_back_on_the_other_side() {
+        let mut k = Kinematics::new(161.0, 50.0, 2.0, -1.0);
+        assert!(!k.apply_bounds(BoundsMode::Wrap, 0.5));
+        assert_eq!((k.x, k.y, k.vx, k.vy), (1.0, 50.0, 2.0, -1.0));
+        // Corners wrap on both axes.
+        let mut k = Kinematics::new(-1.0, -2.0, -1.0, -1.0);
+        k.apply_bounds(BoundsMode::Wrap, 1.0);
+        assert_eq!((k.x, k.y), (159.0, 158.0));
+    }
+
+    #[test]
+    fn overstretched_links_break_at_both_ends() {
+        let mut ecs = empty_world();
+        let a = spawn_ball_at(&mut ecs, 20.0, 40.0, 0.0, 0.0).unwrap();
+        let b = spawn_linked_to(&mut ecs, a).unwrap();
+        let ready = |ecs: &ECS, e| matches!(ecs.components.raining_smiley.get(&e, &ecs.entity_allocator).unwrap().link, BallLink::ReadyToLink);
+        // Stretched, but within MAX_LINK_STRETCH times the rest length: the spring just pulls.
+        ecs.components.kinematics.get_mut(&b, &ecs.entity_allocator).unwrap().x = 20.0 + AVG_SPRING_LENGTH * (MAX_LINK_STRETCH - 1.0);
+        update_smileys_system(&mut ecs);
+        assert!(!ready(&ecs, a) && !ready(&ecs, b));
+        ecs.components.kinematics.get_mut(&b, &ecs.entity_allocator).unwrap().x = 20.0 + AVG_SPRING_LENGTH * (MAX_LINK_STRETCH + 1.0);
+        update_smileys_system(&mut ecs);
+        assert!(ready(&ecs, a) && ready(&ecs, b));
+    }
+
+    #[test]
+    fn tuning_menu_nudges_the_selected_parameter_by_its_step() {
+        let mut tuning = TuningResource::new();
+        let mut pad = Gamepad::default();
+        let mut press = |tuning: &mut TuningResource, buttons| {
+            pad.update(buttons);
+            tuning.handle_input(&pad);
+            pad.update(0);
+            tuning.handle_input(&pad);
+        };
+        press(&mut tuning, BUTTON_DOWN);
+        assert_eq!(tuning.selected, 1);
+        press(&mut tuning, BUTTON_RIGHT);
+        press(&mut tuning, BUTTON_RIGHT);
+        assert_eq!(tuning.spring_stiffness, 0.01 + 2.0 * TuningResource::PARAMS[1].1);
+        // Up from the first parameter wraps around to the last one.
+        press(&mut tuning, BUTTON_UP);
+        press(&mut tuning, BUTTON_UP);
+        assert_eq!(tuning.selected, TuningResource::PARAMS.len() - 1);
+        for _ in 0..100 {
+            press(&mut tuning, BUTTON_LEFT);
+        }
+        assert_eq!(tuning.max_spring_force, 0.0);
+        assert_eq!(tuning.gravity, TuningResource::new().gravity);
+    }
+
+    #[test]
+    fn link_chain_follows_links_once_each() {
+        let mut ecs = empty_world();
+        let balls: Vec<_> = (0..4).map(|i| spawn_ball_at(&mut ecs, i as f32 * 10.0, 40.0, 0.0, 0.0).unwrap()).collect();
+        let link = |ecs: &mut ECS, from: usize, to: usize| {
+            ecs.components.raining_smiley.get_mut(&balls[from], &ecs.entity_allocator).unwrap().link = BallLink::CurrentlyLinked(balls[to]);
+        };
+        // A path 0 -> 1 -> 2, closed into a cycle by 2 -> 0. Ball 3 isn't part of it.
+        link(&mut ecs, 0, 1);
+        link(&mut ecs, 1, 2);
+        link(&mut ecs, 2, 0);
+        assert!(link_chain(balls[0], &ecs.components.raining_smiley, &ecs.entity_allocator) == balls[..3]);
+        assert!(link_chain(balls[1], &ecs.components.raining_smiley, &ecs.entity_allocator) == [balls[1], balls[2], balls[0]]);
+        assert!(link_chain(balls[3], &ecs.components.raining_smiley, &ecs.entity_allocator) == [balls[3]]);
+        ecs.despawn(&balls[3]).unwrap();
+        assert!(link_chain(balls[3], &ecs.components.raining_smiley, &ecs.entity_allocator).is_empty());
+    }
+
+    #[test]
+    fn total_kinetic_energy_sums_every_live_ball() {
+        let mut ecs = empty_world();
+        let kinetic_energy = |ecs: &ECS| total_kinetic_energy(&ecs.components.kinematics, &ecs.entities, &ecs.entity_allocator);
+        assert_eq!(kinetic_energy(&ecs), 0.0);
+        spawn_ball_at(&mut ecs, 10.0, 10.0, 3.0, 4.0).unwrap();
+        spawn_ball_at(&mut ecs, 20.0, 10.0, -2.0, 0.0).unwrap();
+        let gone = spawn_ball_at(&mut ecs, 30.0, 10.0, 10.0, 10.0).unwrap();
+        ecs.despawn(&gone).unwrap();
+        // 0.5 * 25 + 0.5 * 4
+        assert_eq!(kinetic_energy(&ecs), 14.5);
+    }
+
+    #[test]
+    fn elasticity_above_one_doesnt_add_speed_on_bounces() {
+        let mut k = Kinematics::new(100.0, 100.0, 3.0, 2.0);
+        let mut bounces = 0;
+        for _ in 0..500 {
+            let (vx, vy) = (k.vx, k.vy);
+            k.x += k.vx;
+            k.y += k.vy;
+            k.apply_bounds(BoundsMode::Bounce, 1.5);
+            if (k.vx, k.vy) != (vx, vy) {
+                bounces += 1;
             }
-            
-            // Update the kinematics of this ball.
-            if let Ok(pos) = ecs.components.kinematics.get_mut(&e, &ecs.entity_allocator) {
-                if let Ok(phys) = ecs.components.physics.get(&e, &ecs.entity_allocator) {
+            assert!(k.vx.abs() <= 3.0 && k.vy.abs() <= 2.0);
+        }
+        assert!(bounces > 10);
+    }
 
-                    // apply wind
-                    const WIND_SCALER: f32 = 0.03;
-                    pos.vx += ecs.resources.current_wind.0 * WIND_SCALER;
-                    pos.vy += ecs.resources.current_wind.1 * WIND_SCALER;
+    #[test]
+    fn pinned_anchor_stays_put_while_its_linked_ball_swings() {
+        let mut ecs = empty_world();
+        let anchor = spawn_ball_at(&mut ecs, 80.0, 40.0, 0.0, 0.0).unwrap();
+        ecs.components.kinematics.get_mut(&anchor, &ecs.entity_allocator).unwrap().pinned = true;
+        let ball = spawn_linked_to(&mut ecs, anchor).unwrap();
+        let position = |ecs: &ECS, e| {
+            let k = ecs.components.kinematics.get(&e, &ecs.entity_allocator).unwrap();
+            (k.x, k.y)
+        };
+        let start = position(&ecs, ball);
+        for _ in 0..60 {
+            update_smileys_system(&mut ecs);
+            update_kinematics_system(&mut ecs);
+            assert_eq!(position(&ecs, anchor), (80.0, 40.0));
+        }
+        let (x, y) = position(&ecs, ball);
+        assert!(y > start.1 && x < start.0, "the ball should swing down toward the anchor, got {:?}", (x, y));
+        assert!(matches!(ecs.components.raining_smiley.get(&anchor, &ecs.entity_allocator).unwrap().link, BallLink::CurrentlyLinked(e) if e == ball));
+    }
 
-                    pos.vy += ecs.resources.gravity_overall_mult;
+    #[test]
+    fn linked_pair_survives_a_save_round_trip() {
+        let mut ecs = empty_world();
+        let a = spawn_ball_at(&mut ecs, 30.0, 40.0, 1.0, -1.0).unwrap();
+        let b = spawn_linked_to(&mut ecs, a).unwrap();
+        let save = save_balls(&ecs);
+        assert_eq!(save.len(), SAVE_HEADER_LEN + 2 * SAVE_RECORD_LEN);
 
+        // Load into a world with a ball of its own in the same slot as `a` (with a newer generation), which gets replaced.
+        let mut loaded = empty_world();
+        let old = spawn_ball_at(&mut loaded, 0.0, 0.0, 0.0, 0.0).unwrap();
+        loaded.despawn(&old).unwrap();
+        let other = spawn_ball_at(&mut loaded, 0.0, 0.0, 0.0, 0.0).unwrap();
+        load_balls(&mut loaded, &save).unwrap();
+        assert!(loaded.is_alive(&a) && loaded.is_alive(&b) && !loaded.is_alive(&other));
+        assert_eq!(loaded.entities.len(), 2);
+        let link = |e| loaded.components.raining_smiley.get(&e, &loaded.entity_allocator).unwrap().links().collect::<Vec<_>>();
+        assert!(link(a) == [b] && link(b) == [a]);
+        let k = loaded.components.kinematics.get(&a, &loaded.entity_allocator).unwrap();
+        assert_eq!((k.x, k.y, k.vx, k.vy), (30.0, 40.0, 1.0, -1.0));
+        // Saving again gives the very same bytes.
+        assert_eq!(save_balls(&loaded), save);
+    }
 
-                    match k2p {
-                        Some(k2p) => {
-                            
+    #[test]
+    fn save_max_balls_fit_on_the_disk() {
+        let mut ecs = empty_world();
+        for i in 0..SAVE_MAX_BALLS {
+            spawn_ball_at(&mut ecs, i as f32, 0.0, 0.0, 0.0).unwrap();
+        }
+        assert!(save_balls(&ecs).len() <= DISK_SIZE);
+        spawn_ball_at(&mut ecs, 0.0, 0.0, 0.0, 0.0).unwrap();
+        assert!(save_balls(&ecs).len() > DISK_SIZE);
+        assert!(load_balls(&mut ecs, &[SAVE_VERSION + 1, 0, 0]).is_none());
+    }
 
+    #[test]
+    fn spawn_ball_at_puts_a_plain_ball_exactly_there() {
+        let mut ecs = empty_world();
+        let e = spawn_ball_at(&mut ecs, 12.5, 30.0, -1.0, 2.0).unwrap();
+        let k = ecs.components.kinematics.get(&e, &ecs.entity_allocator).unwrap();
+        assert_eq!((k.x, k.y, k.vx, k.vy), (12.5, 30.0, -1.0, 2.0));
+        assert!(matches!(ecs.components.raining_smiley.get(&e, &ecs.entity_allocator).unwrap().link, BallLink::ReadyToLink));
+        assert_eq!(ecs.components.charge.get(&e, &ecs.entity_allocator).unwrap().sign, 0);
+        assert!(ecs.entities == [e]);
+    }
 
-                            // Linked balls slow down over time
-                            pos.vx *= 1.0 - MOTION_DECAY;
-                            pos.vy *= 1.0 - MOTION_DECAY;
+    #[test]
+    fn explosion_pushes_nearby_balls_outward_and_leaves_far_ones_alone() {
+        let mut ecs = empty_world();
+        // Kinematics has the top-left corner, so this is where a ball centered on the explosion would be.
+        let (cx, cy) = (80.0 - BALL_WIDTH / 2.0, 80.0 - BALL_HEIGHT / 2.0);
+        let left = spawn_ball_at(&mut ecs, cx - 10.0, cy, 0.0, 0.0).unwrap();
+        let below = spawn_ball_at(&mut ecs, cx, cy + 20.0, 0.0, 0.0).unwrap();
+        let far = spawn_ball_at(&mut ecs, cx + 60.0, cy, 0.0, 0.0).unwrap();
+        update_spatial_hash_system(&mut ecs);
+        apply_explosion(&mut ecs, 80.0, 80.0, 40.0, 4.0);
+        let velocity = |e| {
+            let k = ecs.components.kinematics.get(&e, &ecs.entity_allocator).unwrap();
+            (k.vx, k.vy)
+        };
+        assert_eq!(velocity(left), (-3.0, 0.0));
+        assert_eq!(velocity(below), (0.0, 2.0));
+        assert_eq!(velocity(far), (0.0, 0.0));
+    }
 
-                            // if it's a linked ball, apply a tension force to its link.
-                            let del_x = k2p.0 - pos.x;
-                            let del_y = k2p.1 - pos.y; 
+    #[test]
+    fn are_linked_only_for_live_balls_linked_both_ways() {
+        let mut ecs = empty_world();
+        let a = spawn_ball_at(&mut ecs, 40.0, 40.0, 0.0, 0.0).unwrap();
+        let b = spawn_linked_to(&mut ecs, a).unwrap();
+        let c = spawn_ball_at(&mut ecs, 80.0, 80.0, 0.0, 0.0).unwrap();
+        let linked = |ecs: &ECS, x, y| are_linked(x, y, &ecs.components.raining_smiley, &ecs.entity_allocator);
+        assert!(linked(&ecs, a, b) && linked(&ecs, b, a));
+        assert!(!linked(&ecs, a, c));
+        // A one-sided link doesn't count.
+        ecs.components.raining_smiley.get_mut(&c, &ecs.entity_allocator).unwrap().link = BallLink::CurrentlyLinked(a);
+        assert!(!linked(&ecs, c, a));
+        ecs.despawn(&b).ok().unwrap();
+        assert!(!linked(&ecs, a, b));
+    }
 
-                            let mag = ((del_x.powi(2) + del_y.powi(2)).sqrt() - (k2p.2 + k2p.3) / 2.0) * 0.01;
+    #[test]
+    fn labels_read_back_the_bytes_they_were_set_to() {
+        let mut ecs = empty_world();
+        let e = spawn_ball_at(&mut ecs, 40.0, 40.0, 0.0, 0.0).unwrap();
+        ecs.components.label.set(&e, &ecs.entity_allocator, LabelComponent::new(b"ab")).unwrap();
+        assert_eq!(ecs.components.label.get(&e, &ecs.entity_allocator).unwrap().as_bytes(), b"ab");
+        // Too long names are cut to 4 bytes, and numbers keep their last 3 digits.
+        assert_eq!(LabelComponent::new(b"abcdef").as_bytes(), b"abcd");
+        assert_eq!(LabelComponent::numbered(b'b', 7).as_bytes(), b"b7");
+        assert_eq!(LabelComponent::numbered(b'b', 1042).as_bytes(), b"b42");
+    }
 
-                            let denom = (del_x.powi(2) + del_y.powi(2)).sqrt();
-                            if denom > 0.0 {
-                                pos.vy += del_y * mag / denom * ecs.resources.gravity_overall_mult;
-                                pos.vx += del_x * mag / denom * ecs.resources.gravity_overall_mult;
-                            }
+    #[test]
+    fn despawn_pair_removes_both_linked_balls() {
+        let mut ecs = empty_world();
+        let a = spawn_ball_at(&mut ecs, 40.0, 40.0, 0.0, 0.0).unwrap();
+        let b = spawn_linked_to(&mut ecs, a).unwrap();
+        let lone = spawn_ball_at(&mut ecs, 80.0, 80.0, 0.0, 0.0).unwrap();
+        assert_eq!(despawn_pair(b, &mut ecs), 2);
+        assert!(!ecs.is_alive(&a) && !ecs.is_alive(&b));
+        assert_eq!(despawn_pair(a, &mut ecs), 0);
+        // A ball without a partner goes alone.
+        assert_eq!(despawn_pair(lone, &mut ecs), 1);
+        assert!(ecs.entities.is_empty());
+    }
 
-                            // if it's a linked ball, remove it when it hits the screen bounds.
-                            if pos.x < 0.0 || pos.x + BALL_WIDTH >= 160.0 || pos.y < 0.0 || pos.y + BALL_HEIGHT >= 160.0 {
-                                if let Ok(()) = ecs.entity_allocator.deallocate(&e) {
-                                    to_rm.push((i, k2p.4));
-                                }
-                            }
-                        }
-                        // if it's an unlinked ball, let it bounce on the edges
-                        None => {
-                            
-
-                            if pos.x + BALL_WIDTH >= 160.0 {
-                                pos.vx *= -phys.collision_elasticity;
-                                pos.x = 160.0 - BALL_WIDTH;
-                            } else if pos.x < 0.0 {
-                                pos.vx *= -phys.collision_elasticity;
-                                pos.x = 0.0;
-                            }
-                            if pos.y + BALL_HEIGHT >= 160.0 {
-                                pos.vy = pos.vy.abs() * -phys.collision_elasticity;
-                                pos.y = 160.0 - BALL_HEIGHT;
-                            } else if pos.y < 0.0 {
-                                pos.y = 0.0;
-                                pos.vy *= -phys.collision_elasticity;
-                            }
-                        },
-                    }
+    #[test]
+    fn link_stats_counts_a_mix_of_linked_and_unlinked_balls() {
+        let mut ecs = empty_world();
+        let stats = link_stats(&ecs.components.raining_smiley, &ecs.entities, &ecs.entity_allocator);
+        assert_eq!((stats.linked, stats.unlinked, stats.avg_spring_length), (0, 0, 0.0));
+        let a = spawn_ball_at(&mut ecs, 40.0, 40.0, 0.0, 0.0).unwrap();
+        let b = spawn_linked_to(&mut ecs, a).unwrap();
+        spawn_ball_at(&mut ecs, 80.0, 80.0, 0.0, 0.0).unwrap();
+        for (e, length) in [(a, 10.0), (b, 20.0)] {
+            ecs.components.raining_smiley.get_mut(&e, &ecs.entity_allocator).unwrap().spring_length = length;
+        }
+        let stats = link_stats(&ecs.components.raining_smiley, &ecs.entities, &ecs.entity_allocator);
+        assert_eq!((stats.linked, stats.unlinked, stats.avg_spring_length), (2, 1, 15.0));
+    }
 
-                    
+    #[test]
+    fn pick_entity_at_picks_the_ball_drawn_on_top() {
+        let mut ecs = empty_world();
+        let a = spawn_ball_at(&mut ecs, 40.0, 40.0, 0.0, 0.0).unwrap();
+        let b = spawn_ball_at(&mut ecs, 44.0, 44.0, 0.0, 0.0).unwrap();
+        let pick = |px, py| pick_entity_at(&ecs.components.kinematics, &ecs.entity_allocator, px, py);
+        assert!(pick(41.0, 41.0) == Some(a));
+        assert!(pick(44.0 + BALL_WIDTH - 1.0, 44.0 + BALL_HEIGHT - 1.0) == Some(b));
+        assert!(pick(100.0, 100.0).is_none());
+        // Where they overlap, the one drawn last (the higher index) is on top.
+        let top = ecs.entity_allocator.iter_live_by_index().last();
+        assert!(pick(45.0, 45.0) == top);
+    }
 
-                    
-                }
-            }
+    #[test]
+    fn grabbed_ball_follows_the_cursor_and_is_thrown_with_the_drag_speed() {
+        let mut ecs = empty_world();
+        let e = spawn_ball_at(&mut ecs, 40.0, 40.0, 0.0, 0.0).unwrap();
+        // Grab it by its center, drag it 2 pixels right and 1 down per frame, then let go.
+        for (i, buttons) in [MOUSE_LEFT, MOUSE_LEFT, MOUSE_LEFT, MOUSE_LEFT, 0].into_iter().enumerate() {
+            ecs.resources.mouse.update(44 + 2 * i as i16, 44 + i as i16, buttons);
+            grab_system(&mut ecs);
+            let k = ecs.components.kinematics.get(&e, &ecs.entity_allocator).unwrap();
+            assert_eq!((k.x, k.y), (40.0 + 2.0 * i as f32, 40.0 + i as f32));
+            assert_eq!(k.pinned, buttons != 0);
         }
-        // remove ball entities when they've been deallocated successfully (and replace them with new ones!)
-        // Also, make sure the other ball that was paired changes state to "ready to link".
-        for (i, other_ball) in to_rm.into_iter().rev() {
-            ecs.entities.remove(i);
-            if let Ok(sm) = ecs.components.raining_smiley.get_mut(&other_ball, &ecs.entity_allocator) {
-                sm.link = BallLink::ReadyToLink;
-            }
-            add_smiley_ball(ecs);
-        }
-    }
-
-    /// Example mutable system: If balls are touching, link them if both have no other link.
-    fn link_smileys_system(ecs: &mut ECS) {
-        const BALL_LINK_RADIUS: f32 = 10.0;
-        let mut links = vec![];
-        let mut linked_entities_this_pass = vec![];
-        for i in 0..ecs.entities.len() {
-            let e1 = &ecs.entities[i];
-            for j in (i+1)..ecs.entities.len() {
-                let e2 = &ecs.entities[j];
-                if let Ok(rs1) = ecs.components.raining_smiley.get(e1, &ecs.entity_allocator) {
-                    if let Ok(rs2) = ecs.components.raining_smiley.get(e2, &ecs.entity_allocator) {
-                        if let Ok(k1) = ecs.components.kinematics.get(e1, &ecs.entity_allocator) {
-                            if let Ok(k2) = ecs.components.kinematics.get(e2, &ecs.entity_allocator) {
-                                if (k1.x - k2.x).powi(2) + (k1.y - k2.y).powi(2) < (BALL_LINK_RADIUS).powi(2) {
-                                    if let BallLink::ReadyToLink = rs1.link {
-                                        if let BallLink::ReadyToLink = rs2.link {
-                                            if !linked_entities_this_pass.contains(e1) && !linked_entities_this_pass.contains(e2) {
-                                                linked_entities_this_pass.push(*e1);
-                                                linked_entities_this_pass.push(*e2);
-                                                links.push((e1, e2));
-                                            }  
-                                        }
-                                    }
-                                }
-                            } 
-                        }
-                    } 
-                }   
-            }      
+        let k = ecs.components.kinematics.get(&e, &ecs.entity_allocator).unwrap();
+        assert_eq!((k.vx, k.vy), (2.0, 1.0));
+        assert!(ecs.resources.grab.grabbed.is_none());
+    }
+
+    #[test]
+    fn soft_wall_pushes_a_ball_back_over_several_frames() {
+        let mode = BoundsMode::SoftWall { stiffness: 0.1 };
+        let start = 160.0 - BALL_WIDTH + 10.0;
+        let mut k = Kinematics::new(start, 50.0, 0.0, 0.0);
+        assert!(!k.apply_bounds(mode, 1.0));
+        // Not snapped back, just pushed.
+        assert_eq!((k.x, k.vx), (start, -1.0));
+        let mut frames = 0;
+        while k.x + BALL_WIDTH > 160.0 {
+            k.x += k.vx;
+            k.apply_bounds(mode, 1.0);
+            frames += 1;
+            assert!(frames < 20, "still {} past the wall", k.x + BALL_WIDTH - 160.0);
         }
+        assert!(frames > 1);
+    }
 
-        for (e1, e2) in links {
-            if let Ok(rsm1) = ecs.components.raining_smiley.get_mut(e1, &ecs.entity_allocator) {
-                rsm1.link = BallLink::CurrentlyLinked(*e2);
-            }
-            if let Ok(rsm2) = ecs.components.raining_smiley.get_mut(e2, &ecs.entity_allocator) {
-                rsm2.link = BallLink::CurrentlyLinked(*e1);
-            }
+    #[test]
+    fn directional_sprite_looks_the_way_the_ball_moves() {
+        let cases: [((f32, f32), &[u8], u32); 6] = [
+            ((0.1, -0.2), &SMILEY, BLIT_1BPP),
+            ((2.0, 1.0), &SMILEY_RIGHT, BLIT_1BPP),
+            ((-2.0, 1.0), &SMILEY_RIGHT, BLIT_1BPP | BLIT_FLIP_X),
+            ((0.5, -2.0), &SMILEY_UP, BLIT_1BPP),
+            ((-0.5, 2.0), &SMILEY_DOWN, BLIT_1BPP),
+            // Diagonals look sideways.
+            ((1.0, 1.0), &SMILEY_RIGHT, BLIT_1BPP),
+        ];
+        for ((vx, vy), sprite, flags) in cases {
+            let (got_sprite, got_flags) = directional_sprite(vx, vy);
+            assert!(core::ptr::eq(got_sprite, sprite), "wrong sprite for ({}, {})", vx, vy);
+            assert_eq!(got_flags, flags);
         }
-        
     }
 
-    unsafe { *DRAW_COLORS = 2 }
+    #[test]
+    fn tension_color_goes_from_slack_to_stretched() {
+        assert_eq!(tension_color(5.0, 10.0), 0x0002);
+        assert_eq!(tension_color(7.5, 10.0), 0x0003);
+        assert_eq!(tension_color(10.0, 10.0), 0x0003);
+        assert_eq!(tension_color(12.5, 10.0), 0x0003);
+        assert_eq!(tension_color(13.0, 10.0), 0x0004);
+    }
 
-    let gamepad = unsafe { *GAMEPAD1 };
-    ecs.resources.gravity_overall_mult = match gamepad != 0 {
-        true => 0.1,
-        false => 0.03
-    };
-    
-    // Example input mutable system: this stores game input for other systems to use later (via the resources struct in the ecs struct).
-    fn update_input_system(ecs: &mut ECS) {
-        unsafe {
-            let mut vx = 0.0;
-            let mut vy = 0.0;
-            if *GAMEPAD1 & BUTTON_LEFT != 0 {
-                vx -= 1.0;
-            } else if *GAMEPAD1 & BUTTON_RIGHT != 0 {
-                vx += 1.0;
-            }
-            if *GAMEPAD1 & BUTTON_UP != 0 {
-                vy -= 1.0;
-            } else if *GAMEPAD1 & BUTTON_DOWN != 0 {
-                vy += 1.0;
+    #[test]
+    fn chain_centroid_averages_the_linked_balls() {
+        let mut ecs = empty_world();
+        let a = spawn_ball_at(&mut ecs, 10.0, 20.0, 0.0, 0.0).unwrap();
+        let b = spawn_linked_to(&mut ecs, a).unwrap();
+        spawn_ball_at(&mut ecs, 100.0, 100.0, 0.0, 0.0).unwrap();
+        let centroid = |ecs: &ECS, e| chain_centroid(e, &ecs.components.kinematics, &ecs.components.raining_smiley, &ecs.entity_allocator);
+        let expected = Some((10.0 + AVG_SPRING_LENGTH / 2.0, 20.0));
+        assert_eq!(centroid(&ecs, a), expected);
+        assert_eq!(centroid(&ecs, b), expected);
+        ecs.despawn(&b).unwrap();
+        assert_eq!(centroid(&ecs, a), Some((10.0, 20.0)));
+        assert_eq!(centroid(&ecs, b), None);
+    }
+
+    // Input comes from the (mock) gamepad registers.
+    #[cfg(feature = "headless")]
+    #[test]
+    fn pausing_freezes_physics_on_the_frame_it_is_pressed() {
+        let _lock = wasm4::mock::lock();
+        let mut ecs = empty_world();
+        let e = spawn_ball_at(&mut ecs, 40.0, 40.0, 1.0, 0.0).unwrap();
+        let pos = |ecs: &ECS| {
+            let k = ecs.components.kinematics.get(&e, &ecs.entity_allocator).unwrap();
+            (k.x, k.y)
+        };
+        let start = pos(&ecs);
+        headless::set_gamepad(BUTTON_1);
+        run_systems(&mut ecs);
+        assert!(ecs.resources.paused);
+        assert_eq!(pos(&ecs), start);
+        // Input is still read while paused: letting go and pressing again unpauses, and things move on that frame.
+        headless::set_gamepad(0);
+        run_systems(&mut ecs);
+        assert_eq!(pos(&ecs), start);
+        headless::set_gamepad(BUTTON_1);
+        run_systems(&mut ecs);
+        assert!(!ecs.resources.paused);
+        assert_ne!(pos(&ecs), start);
+    }
+
+    // Runs whole frames, which read the (mock) input registers.
+    #[cfg(feature = "headless")]
+    #[test]
+    fn charges_dont_build_up_velocity_while_paused() {
+        let _lock = wasm4::mock::lock();
+        let charged_pair = || {
+            let mut ecs = empty_world();
+            let left = spawn_ball_at(&mut ecs, 40.0, 40.0, 0.0, 0.0).unwrap();
+            let right = spawn_ball_at(&mut ecs, 46.0, 40.0, 0.0, 0.0).unwrap();
+            for (e, sign) in [(left, 1), (right, -1)] {
+                ecs.components.charge.set(&e, &ecs.entity_allocator, ChargeComponent{sign, strength: CHARGE_STRENGTH}).unwrap();
             }
-            ecs.resources.current_wind = (vx, vy);
+            (ecs, [left, right])
+        };
+        let velocities = |ecs: &ECS, balls: [Entity; 2]| {
+            balls.map(|e| {
+                let k = ecs.components.kinematics.get(&e, &ecs.entity_allocator).unwrap();
+                (k.vx, k.vy)
+            })
+        };
+        let (mut paused, balls) = charged_pair();
+        let start = velocities(&paused, balls);
+        paused.resources.paused = true;
+        for _ in 0..30 {
+            run_systems(&mut paused);
+            assert_eq!(velocities(&paused, balls), start);
         }
+        // The first frame back is just like the first frame of a world that was never paused.
+        paused.resources.paused = false;
+        run_systems(&mut paused);
+        let (mut never_paused, _) = charged_pair();
+        run_systems(&mut never_paused);
+        assert_ne!(velocities(&paused, balls), start);
+        assert_eq!(velocities(&paused, balls), velocities(&never_paused, balls));
     }
 
-    fn add_balls_if_all_linked(ecs: &mut ECS) {
-        let mut unlinked_count = 0;
-        for e in &ecs.entities {
-            if let Ok(b) = ecs.components.raining_smiley.get(e, &ecs.entity_allocator) {
-                if let BallLink::ReadyToLink = b.link {
-                    unlinked_count += 1;
-                }
+    // Input comes from the (mock) gamepad registers.
+    #[cfg(feature = "headless")]
+    #[test]
+    fn holding_a_button_slows_gravity_on_the_same_frame() {
+        let _lock = wasm4::mock::lock();
+        let mut ecs = empty_world();
+        headless::set_gamepad(BUTTON_DOWN);
+        update_input_system(&mut ecs);
+        assert_eq!(ecs.resources.gravity_overall_mult, 0.1);
+        headless::set_gamepad(0);
+        update_input_system(&mut ecs);
+        assert_eq!(ecs.resources.gravity_overall_mult, ecs.resources.tuning.gravity);
+    }
+
+    #[cfg(feature = "headless")]
+    #[test]
+    fn wind_ramps_up_to_the_held_direction_and_dies_down() {
+        let _lock = wasm4::mock::lock();
+        let mut ecs = empty_world();
+        headless::set_gamepad(BUTTON_RIGHT | BUTTON_UP);
+        update_input_system(&mut ecs);
+        let (wx, wy) = ecs.resources.current_wind;
+        assert!(wx > 0.0 && wx < 1.0 && wy < 0.0 && wy > -1.0);
+        for _ in 0..20 {
+            update_input_system(&mut ecs);
+        }
+        assert_eq!(ecs.resources.current_wind, (1.0, -1.0));
+        headless::set_gamepad(0);
+        update_input_system(&mut ecs);
+        assert!(ecs.resources.current_wind.0 < 1.0 && ecs.resources.current_wind.0 > 0.0);
+        for _ in 0..20 {
+            update_input_system(&mut ecs);
+        }
+        assert_eq!(ecs.resources.current_wind, (0.0, 0.0));
+    }
+
+    #[cfg(feature = "headless")]
+    #[test]
+    fn headless_frames_keep_the_live_count_in_range() {
+        let _lock = wasm4::mock::lock();
+        headless::reset();
+        assert_eq!(headless::live_entity_count(), 0);
+        for frame in 0..600 {
+            // Some scripted input along the way: wind, a pause and unpause, and explosions under the cursor.
+            headless::set_gamepad(match frame {
+                100..=160 => BUTTON_RIGHT,
+                200 | 230 => BUTTON_1,
+                _ => 0,
+            });
+            headless::set_mouse(80, 100, if frame % 150 == 75 { MOUSE_RIGHT } else { 0 });
+            headless::step();
+            assert!(headless::live_entity_count() <= MAX_N_ENTITIES, "{} live on frame {}", headless::live_entity_count(), frame);
+        }
+        assert!(headless::live_entity_count() > 0);
+        headless::reset();
+    }
+
+    #[test]
+    fn maintain_population_converges_on_the_target() {
+        let mut ecs = empty_world();
+        let oldest = spawn_ball_at(&mut ecs, 40.0, 40.0, 0.0, 0.0).unwrap();
+        assert_eq!(maintain_population(&mut ecs, 30), 30);
+        assert_eq!(ecs.entities.len(), 30);
+        // Link the oldest ball to the newest, which should be freed up when the oldest goes.
+        let newest = *ecs.entities.last().unwrap();
+        for (e, other) in [(oldest, newest), (newest, oldest)] {
+            ecs.components.raining_smiley.get_mut(&e, &ecs.entity_allocator).unwrap().link = BallLink::CurrentlyLinked(other);
+        }
+        assert_eq!(maintain_population(&mut ecs, 10), 10);
+        assert!(!ecs.is_alive(&oldest) && ecs.is_alive(&newest));
+        assert!(matches!(ecs.components.raining_smiley.get(&newest, &ecs.entity_allocator).unwrap().link, BallLink::ReadyToLink));
+        assert_eq!(maintain_population(&mut ecs, MAX_N_ENTITIES + 10), MAX_N_ENTITIES);
+        assert_eq!(maintain_population(&mut ecs, 0), 0);
+        assert!(ecs.entities.is_empty());
+    }
+
+    #[cfg(feature = "headless")]
+    #[test]
+    fn hard_bounce_freezes_physics_for_the_hitstop_frames() {
+        let _lock = wasm4::mock::lock();
+        let mut ecs = empty_world();
+        // A soft bounce doesn't trigger it.
+        let e = spawn_ball_at(&mut ecs, 160.0 - BALL_WIDTH + 1.0, 80.0, 1.0, 0.0).unwrap();
+        update_smileys_system(&mut ecs);
+        assert_eq!(ecs.resources.hitstop_frames, 0);
+        ecs.components.kinematics.get_mut(&e, &ecs.entity_allocator).unwrap().vx = 4.0;
+        update_smileys_system(&mut ecs);
+        assert_eq!(ecs.resources.hitstop_frames, HITSTOP_FRAMES);
+        let pos = |ecs: &ECS| {
+            let k = ecs.components.kinematics.get(&e, &ecs.entity_allocator).unwrap();
+            (k.x, k.y)
+        };
+        let start = pos(&ecs);
+        for _ in 0..HITSTOP_FRAMES {
+            run_systems(&mut ecs);
+            assert_eq!(pos(&ecs), start);
+        }
+        run_systems(&mut ecs);
+        assert_ne!(pos(&ecs), start);
+    }
+
+    #[test]
+    fn delta_between_saves_rebuilds_the_current_save() {
+        let mut ecs = empty_world();
+        let balls: Vec<_> = (0..4).map(|i| spawn_ball_at(&mut ecs, 20.0 * i as f32, 30.0, 0.0, 0.0).unwrap()).collect();
+        let previous = save_balls(&ecs);
+        assert_eq!(diff_saves(&previous, &previous).unwrap().len(), 5);
+        // One ball moves, one is gone, and one is new.
+        ecs.components.kinematics.get_mut(&balls[0], &ecs.entity_allocator).unwrap().x = 99.0;
+        ecs.despawn(&balls[1]).unwrap();
+        spawn_ball_at(&mut ecs, 50.0, 50.0, 1.0, 1.0).unwrap();
+        let current = save_balls(&ecs);
+        let delta = diff_saves(&previous, &current).unwrap();
+        assert!(delta.len() < current.len());
+        assert_eq!(apply_delta(&previous, &delta).unwrap(), current);
+        // The moved ball only sends its kinematics, which the save without it can't fill in.
+        let empty = save_balls(&empty_world());
+        assert!(apply_delta(&empty, &delta).is_none());
+    }
+
+    #[test]
+    fn spring_force_is_capped_on_a_badly_stretched_link() {
+        let pull = |max_spring_force| {
+            let mut ecs = empty_world();
+            ecs.resources.tuning.max_spring_force = max_spring_force;
+            let a = spawn_ball_at(&mut ecs, 10.0, 40.0, 0.0, 0.0).unwrap();
+            let b = spawn_linked_to(&mut ecs, a).unwrap();
+            // Just short of snapping.
+            ecs.components.kinematics.get_mut(&b, &ecs.entity_allocator).unwrap().x = 10.0 + AVG_SPRING_LENGTH * MAX_LINK_STRETCH - 1.0;
+            update_smileys_system(&mut ecs);
+            (ecs.components.kinematics.get(&a, &ecs.entity_allocator).unwrap().vx, ecs.resources.gravity_overall_mult)
+        };
+        let (capped, mult) = pull(0.05);
+        assert!(capped > 0.0 && capped <= 0.05 * mult + 1e-6);
+        // Without the cap, the same stretch pulls harder.
+        let (uncapped, _) = pull(f32::INFINITY);
+        assert!(uncapped > capped);
+    }
+
+    #[test]
+    fn disabled_ball_ignores_forces_but_stays_on_screen() {
+        let mut ecs = empty_world();
+        let a = spawn_ball_at(&mut ecs, 40.0, 40.0, 1.0, 1.0).unwrap();
+        let b = spawn_linked_to(&mut ecs, a).unwrap();
+        // Stretch the link, so the spring pulls on both ends.
+        ecs.components.kinematics.get_mut(&b, &ecs.entity_allocator).unwrap().x += AVG_SPRING_LENGTH;
+        ecs.components.kinematics.get_mut(&a, &ecs.entity_allocator).unwrap().disabled = true;
+        let before = |ecs: &ECS, e| {
+            let k = ecs.components.kinematics.get(&e, &ecs.entity_allocator).unwrap();
+            (k.x, k.y, k.vx, k.vy)
+        };
+        let (a0, b0) = (before(&ecs, a), before(&ecs, b));
+        update_spatial_hash_system(&mut ecs);
+        apply_explosion(&mut ecs, 44.0, 50.0, 40.0, 4.0);
+        update_smileys_system(&mut ecs);
+        update_kinematics_system(&mut ecs);
+        assert_eq!(before(&ecs, a), a0);
+        assert_ne!(before(&ecs, b), b0);
+        // Still there to be drawn (and picked) where it was.
+        assert!(ecs.is_alive(&a));
+        assert!(pick_entity_at(&ecs.components.kinematics, &ecs.entity_allocator, 41.0, 41.0) == Some(a));
+    }
+
+    #[test]
+    fn only_max_links_per_frame_links_form_at_once() {
+        let linked = |ecs: &ECS| link_stats(&ecs.components.raining_smiley, &ecs.entities, &ecs.entity_allocator).linked;
+        let three_pairs = |max_links_per_frame| {
+            let mut ecs = empty_world();
+            ecs.resources.max_links_per_frame = max_links_per_frame;
+            for i in 0..3 {
+                let x = 10.0 + 40.0 * i as f32;
+                spawn_ball_at(&mut ecs, x, 20.0, 0.0, 0.0).unwrap();
+                spawn_ball_at(&mut ecs, x + 5.0, 20.0, 0.0, 0.0).unwrap();
             }
+            update_spatial_hash_system(&mut ecs);
+            link_smileys_system(&mut ecs);
+            ecs
+        };
+        let mut capped = three_pairs(1);
+        assert_eq!(linked(&capped), 2);
+        let uncapped = three_pairs(MAX_CHANGES_PER_FRAME);
+        assert_eq!(linked(&uncapped), 6);
+        // The cap doesn't cut the pass short, so every pair is still checked (and counted).
+        assert_eq!(capped.resources.perf.pair_checks, uncapped.resources.perf.pair_checks);
+        // The pairs that had to wait link on the next passes.
+        link_smileys_system(&mut capped);
+        link_smileys_system(&mut capped);
+        assert_eq!(linked(&capped), 6);
+    }
+
+    #[test]
+    fn restoring_a_resource_snapshot_puts_back_every_value() {
+        let mut ecs = empty_world();
+        let r = &mut ecs.resources;
+        r.rng.next();
+        let snapshot = r.snapshot();
+        let values = |r: &mut GameResources| (r.gravity_overall_mult, r.current_wind, r.tuning.value(0), r.frame, matches!(r.bounds_mode, BoundsMode::Bounce));
+        let saved = values(r);
+        let next_random = Rng::from_state(r.rng.state()).next();
+        r.rng.next();
+        r.gravity_overall_mult = 5.0;
+        r.current_wind = (1.0, -1.0);
+        *r.tuning.value_mut(0) += 1.0;
+        r.frame += 10;
+        r.bounds_mode = BoundsMode::Wrap;
+        r.restore(&snapshot);
+        assert_eq!(values(r), saved);
+        assert_eq!(r.rng.next(), next_random);
+    }
+
+    #[test]
+    fn balls_accelerate_toward_a_gravity_source() {
+        let mut ecs = empty_world();
+        // The ball's center ends up at (60, 60), 20 pixels up and left of the source.
+        let e = spawn_ball_at(&mut ecs, 60.0 - BALL_WIDTH / 2.0, 60.0 - BALL_HEIGHT / 2.0, 0.0, 0.0).unwrap();
+        let _ = ecs.resources.gravity_sources.push(GravitySource { x: 80.0, y: 80.0, strength: 200.0 });
+        update_smileys_system(&mut ecs);
+        let k = ecs.components.kinematics.get(&e, &ecs.entity_allocator).unwrap();
+        assert!(k.vx > 0.0 && (k.vx - k.vy).abs() < 1e-6);
+        // Inverse square: twice as far is a quarter of the pull, and it's capped close in.
+        let source = GravitySource { x: 0.0, y: 0.0, strength: 400.0 };
+        assert_eq!(source.pull(10.0, 0.0), (-4.0, 0.0));
+        assert_eq!(source.pull(0.0, 20.0), (0.0, -1.0));
+        assert_eq!(source.pull(1.0, 0.0), source.pull(GravitySource::MIN_DIST, 0.0));
+    }
+
+    #[test]
+    fn damped_balls_settle_eventually() {
+        let mut ecs = empty_world();
+        let settled = |ecs: &ECS| is_settled(&ecs.components.kinematics, &ecs.entities, &ecs.entity_allocator, 0.01);
+        assert!(settled(&ecs));
+        spawn_ball_at(&mut ecs, 40.0, 40.0, 2.0, -1.0).unwrap();
+        spawn_ball_at(&mut ecs, 80.0, 80.0, 0.0, 0.0).unwrap();
+        assert!(!settled(&ecs));
+        let frames = (0..1000).position(|_| {
+            update_kinematics_system(&mut ecs);
+            settled(&ecs)
+        });
+        assert!(frames.is_some_and(|f| f > 10));
+    }
+
+    #[test]
+    fn circle_formation_puts_every_ball_on_the_circle() {
+        let mut ecs = empty_world();
+        assert_eq!(spawn_formation(&mut ecs, Formation::Circle { radius: 30.0 }, (80.0, 80.0), 8), 8);
+        let centers: Vec<_> = ecs.entities.iter().map(|e| {
+            let k = ecs.components.kinematics.get(e, &ecs.entity_allocator).unwrap();
+            (k.x + BALL_WIDTH / 2.0, k.y + BALL_HEIGHT / 2.0)
+        }).collect();
+        assert_eq!(centers[0], (110.0, 80.0));
+        for (i, &(x, y)) in centers.iter().enumerate() {
+            assert!(((x - 80.0).hypot(y - 80.0) - 30.0).abs() < 1e-4);
+            // Evenly spaced: neighbours are all the same distance apart.
+            let (nx, ny) = centers[(i + 1) % centers.len()];
+            assert!(((x - nx).hypot(y - ny) - 2.0 * 30.0 * (core::f32::consts::PI / 8.0).sin()).abs() < 1e-3);
         }
-        if unlinked_count == 0 {
-            add_smiley_ball(ecs);
-            add_smiley_ball(ecs);
+        // The same call always lays out the same scene.
+        let mut again = empty_world();
+        spawn_formation(&mut again, Formation::Circle { radius: 30.0 }, (80.0, 80.0), 8);
+        assert_eq!(save_balls(&again), save_balls(&ecs));
+    }
+
+    #[test]
+    fn collision_normal_points_from_b_to_a_with_a_fallback_for_coincident_balls() {
+        let at = |x, y| Kinematics::new(x, y, 0.0, 0.0);
+        assert_eq!(collision_normal(&at(13.0, 14.0), &at(10.0, 10.0)), (0.6, 0.8));
+        assert_eq!(collision_normal(&at(10.0, 10.0), &at(13.0, 14.0)), (-0.6, -0.8));
+        assert_eq!(collision_normal(&at(5.0, 5.0), &at(5.0, 5.0)), (1.0, 0.0));
+    }
+
+    #[test]
+    fn startup_ramp_reaches_the_target_without_exceeding_the_per_frame_cap() {
+        let mut ecs = init_ecs(InitConfig { count: 23, ramp_per_frame: Some(5), ..InitConfig::default() });
+        assert_eq!(ecs.entity_allocator.live_count(), 0);
+        let mut frames = 0;
+        while ecs.resources.startup_ramp.is_some() {
+            let before = ecs.entity_allocator.live_count();
+            startup_ramp_system(&mut ecs);
+            assert!(ecs.entity_allocator.live_count() - before <= 5);
+            frames += 1;
         }
+        assert_eq!(frames, 5);
+        assert_eq!(ecs.entity_allocator.live_count(), 23);
+        assert_eq!(ecs.entities.len(), 23);
     }
 
+    #[test]
+    fn overlapping_balls_are_separated_to_exactly_the_radius() {
+        let mut ecs = empty_world();
+        let a = spawn_ball_at(&mut ecs, 40.0, 40.0, 1.0, 0.0).unwrap();
+        let b = spawn_ball_at(&mut ecs, 43.0, 44.0, 0.0, -1.0).unwrap();
+        update_spatial_hash_system(&mut ecs);
+        separate_overlapping(&mut ecs, 10.0);
+        let (ka, kb) = ecs.components.kinematics.get2_mut(&a, &b, &ecs.entity_allocator).ok().unwrap();
+        let dist = ((ka.x - kb.x).powi(2) + (ka.y - kb.y).powi(2)).sqrt();
+        assert!((dist - 10.0).abs() < 1e-4, "{dist}");
+        // Each moved half the overlap, along the line between them, and kept its velocity.
+        assert!((ka.x - 38.5).abs() < 1e-4 && (ka.y - 38.0).abs() < 1e-4);
+        assert_eq!((ka.vx, ka.vy, kb.vx, kb.vy), (1.0, 0.0, 0.0, -1.0));
+    }
 
-    // Running the game is just playing forward all the systems!!
+    #[test]
+    fn exported_positions_match_get_in_entity_order() {
+        let mut ecs = empty_world();
+        for i in 0..4 {
+            spawn_ball_at(&mut ecs, 10.0 * i as f32, 5.0 + i as f32, 0.0, 0.0).unwrap();
+        }
+        let mut out = vec![99.0];
+        export_positions(&ecs.components.kinematics, &ecs.entities, &ecs.entity_allocator, &mut out);
+        let expected: Vec<f32> = ecs
+            .entities
+            .iter()
+            .map(|e| ecs.components.kinematics.get(e, &ecs.entity_allocator).unwrap())
+            .flat_map(|k| [k.x, k.y])
+            .collect();
+        assert_eq!(out, expected);
+        assert_eq!(out.len(), 8);
+    }
 
-    // mutable systems
-    update_input_system(&mut ecs);
-    update_smileys_system(&mut ecs);
-    update_kinematics_system(&mut ecs);
-    link_smileys_system(&mut ecs);
-    add_balls_if_all_linked(&mut ecs);
+    #[test]
+    fn balls_only_link_within_their_team() {
+        let mut ecs = empty_world();
+        let mut on_team = |x, team| {
+            let e = spawn_ball_at(&mut ecs, x, 40.0, 0.0, 0.0).unwrap();
+            ecs.components.team.set(&e, &ecs.entity_allocator, Some(TeamComponent { team })).ok().unwrap();
+            e
+        };
+        let (red1, blue1) = (on_team(10.0, 0), on_team(15.0, 1));
+        let (blue2, blue3) = (on_team(100.0, 1), on_team(105.0, 1));
+        update_spatial_hash_system(&mut ecs);
+        link_smileys_system(&mut ecs);
+        let smileys = &ecs.components.raining_smiley;
+        assert!(!are_linked(red1, blue1, smileys, &ecs.entity_allocator));
+        assert!(are_linked(blue2, blue3, smileys, &ecs.entity_allocator));
+    }
 
+    #[test]
+    fn teams_survive_a_save_round_trip() {
+        let mut ecs = empty_world();
+        let red = spawn_ball_at(&mut ecs, 10.0, 40.0, 0.0, 0.0).unwrap();
+        let blue = spawn_ball_at(&mut ecs, 15.0, 40.0, 0.0, 0.0).unwrap();
+        let no_team = spawn_ball_at(&mut ecs, 100.0, 40.0, 0.0, 0.0).unwrap();
+        for (e, team) in [(red, 0), (blue, 1)] {
+            ecs.components.team.set(&e, &ecs.entity_allocator, Some(TeamComponent { team })).ok().unwrap();
+        }
+        let mut loaded = empty_world();
+        load_balls(&mut loaded, &save_balls(&ecs)).unwrap();
+        let team = |e| loaded.components.team.get(&e, &loaded.entity_allocator).ok().copied().flatten().map(|t| t.team);
+        assert_eq!((team(red), team(blue), team(no_team)), (Some(0), Some(1), None));
+        update_spatial_hash_system(&mut loaded);
+        link_smileys_system(&mut loaded);
+        assert!(!are_linked(red, blue, &loaded.components.raining_smiley, &loaded.entity_allocator));
+    }
 
-    // immutable systems
-    draw_smileys_system(&ecs);
+    // Replacement balls are spawned under the (mock) mouse.
+    #[cfg(feature = "headless")]
+    #[test]
+    fn a_replacement_ball_joins_the_team_of_the_ball_left_behind() {
+        let _lock = wasm4::mock::lock();
+        let mut ecs = empty_world();
+        let removed = spawn_ball_at(&mut ecs, 40.0, 40.0, 0.0, 0.0).unwrap();
+        let left_behind = spawn_linked_to(&mut ecs, removed).unwrap();
+        // Not the team the spawn count would give the next ball.
+        for e in [removed, left_behind] {
+            ecs.components.team.set(&e, &ecs.entity_allocator, Some(TeamComponent { team: 1 })).ok().unwrap();
+        }
+        ecs.components.kinematics.get_mut(&removed, &ecs.entity_allocator).unwrap().x = -50.0;
+        update_smileys_system(&mut ecs);
+        assert!(!ecs.is_alive(&removed));
+        let replacement = *ecs.entities.iter().find(|&&e| e != left_behind).unwrap();
+        let team = |ecs: &ECS, e| ecs.components.team.get(&e, &ecs.entity_allocator).ok().copied().flatten().map(|t| t.team);
+        assert_eq!(team(&ecs, replacement), Some(1));
+        let (x, y) = {
+            let k = ecs.components.kinematics.get(&left_behind, &ecs.entity_allocator).unwrap();
+            (k.x, k.y)
+        };
+        *ecs.components.kinematics.get_mut(&replacement, &ecs.entity_allocator).unwrap() = Kinematics::new(x + 5.0, y, 0.0, 0.0);
+        update_spatial_hash_system(&mut ecs);
+        link_smileys_system(&mut ecs);
+        assert!(are_linked(left_behind, replacement, &ecs.components.raining_smiley, &ecs.entity_allocator));
+        // With everything linked, a new pair comes in, on one team even though the spawn count is now odd.
+        add_balls_if_all_linked(&mut ecs);
+        let new_pair: Vec<_> = ecs.entities[2..].iter().map(|&e| team(&ecs, e)).collect();
+        assert_eq!(new_pair.len(), 2);
+        assert!(new_pair[0].is_some() && new_pair[0] == new_pair[1]);
+    }
 
-    unsafe { *DRAW_COLORS = 0x0004 }
-    text("rust-wasm4-mini-ecs", 3, 150);
+    #[test]
+    fn spawned_pairs_share_a_team_and_alternate_between_teams() {
+        let mut ecs = empty_world();
+        for _ in 0..8 {
+            add_smiley_ball_near(&mut ecs, 80.0, 80.0, 20.0).ok().unwrap();
+        }
+        let teams: Vec<u8> =
+            ecs.entities.iter().map(|e| ecs.components.team.get(e, &ecs.entity_allocator).ok().copied().flatten().unwrap().team).collect();
+        assert_eq!(teams, [0, 0, 1, 1, 0, 0, 1, 1]);
+    }
 }
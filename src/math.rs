@@ -0,0 +1,78 @@
+// Small float helpers for animating values over time.
+
+/// Blend from `a` (`t = 0`) to `b` (`t = 1`). `t` outside of [0, 1] extrapolates.
+pub fn lerp(a: f32, b: f32, t: f32) -> f32 {
+    a + (b - a) * t
+}
+
+/// Keep `x` within `[lo, hi]`. Unlike `f32::clamp`, this doesn't panic if `lo > hi` (it returns `hi`).
+pub fn clamp(x: f32, lo: f32, hi: f32) -> f32 {
+    x.max(lo).min(hi)
+}
+
+/// Smoothstep easing: starts and ends slowly, maps 0 to 0 and 1 to 1. `t` is clamped to [0, 1] first.
+pub fn ease_in_out(t: f32) -> f32 {
+    let t = clamp(t, 0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+/// Move `current` toward `target` by at most `max_delta`, without overshooting. Call it every frame to ease a value
+/// toward a target at a fixed rate instead of snapping to it.
+pub fn approach(current: f32, target: f32, max_delta: f32) -> f32 {
+    if current < target {
+        (current + max_delta).min(target)
+    } else {
+        (current - max_delta).max(target)
+    }
+}
+
+/// Inverse-square falloff (`strength / dist²`), e.g. for gravity toward a point or a charge-like force. Distances below
+/// `min_dist` count as `min_dist`, so the force tops out at `strength / min_dist²` instead of blowing up (or going NaN)
+/// as things get close. `min_dist` should be above 0.
+pub fn inverse_square_falloff(dist: f32, strength: f32, min_dist: f32) -> f32 {
+    let d = dist.abs().max(min_dist);
+    strength / (d * d)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lerp_and_clamp() {
+        assert_eq!(lerp(2.0, 6.0, 0.0), 2.0);
+        assert_eq!(lerp(2.0, 6.0, 0.25), 3.0);
+        assert_eq!(lerp(2.0, 6.0, 1.5), 8.0);
+        assert_eq!(clamp(5.0, 0.0, 1.0), 1.0);
+        assert_eq!(clamp(-5.0, 0.0, 1.0), 0.0);
+        assert_eq!(clamp(0.5, 2.0, 1.0), 1.0);
+    }
+
+    #[test]
+    fn ease_in_out_is_smooth_and_symmetric() {
+        assert_eq!(ease_in_out(0.0), 0.0);
+        assert_eq!(ease_in_out(0.5), 0.5);
+        assert_eq!(ease_in_out(1.0), 1.0);
+        assert_eq!(ease_in_out(2.0), 1.0);
+        assert!(ease_in_out(0.1) < 0.1);
+        assert!((ease_in_out(0.3) + ease_in_out(0.7) - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn approach_never_overshoots() {
+        assert_eq!(approach(0.0, 1.0, 0.3), 0.3);
+        assert_eq!(approach(0.9, 1.0, 0.3), 1.0);
+        assert_eq!(approach(0.0, -1.0, 0.3), -0.3);
+        assert_eq!(approach(1.0, 1.0, 0.3), 1.0);
+    }
+
+    #[test]
+    fn inverse_square_falloff_is_bounded_below_min_dist() {
+        assert_eq!(inverse_square_falloff(4.0, 32.0, 1.0), 2.0);
+        assert_eq!(inverse_square_falloff(-4.0, 32.0, 1.0), 2.0);
+        for dist in [2.0, 0.5, 0.0, -0.0] {
+            assert_eq!(inverse_square_falloff(dist, 32.0, 2.0), 8.0);
+        }
+        assert!(inverse_square_falloff(1e-20, 1.0, 0.1).is_finite());
+    }
+}
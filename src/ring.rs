@@ -0,0 +1,147 @@
+// Fixed-capacity data structures, for lists and history that never grow the heap.
+
+/// Keeps the last `N` pushed items (`N` must be above 0). Once full, each push overwrites the oldest item.
+/// All the storage is inline, so it never allocates.
+pub struct RingBuffer<T, const N: usize> {
+    items: [Option<T>; N],
+    // Where the next item goes (once full, that's the oldest one).
+    next: usize,
+}
+
+impl<T, const N: usize> RingBuffer<T, N> {
+    pub fn new() -> RingBuffer<T, N> {
+        RingBuffer {
+            items: core::array::from_fn(|_| None),
+            next: 0,
+        }
+    }
+
+    pub fn push(&mut self, item: T) {
+        self.items[self.next] = Some(item);
+        self.next = (self.next + 1) % N;
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.iter().filter(|item| item.is_some()).count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.iter().all(|item| item.is_none())
+    }
+
+    /// The stored items, newest first.
+    pub fn iter(&self) -> impl Iterator<Item = &T> + '_ {
+        (1..=N).filter_map(move |i| self.items[(self.next + N - i) % N].as_ref())
+    }
+}
+
+impl<T, const N: usize> Default for RingBuffer<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A list with room for at most `N` items, stored inline (e.g. on the stack), so filling it never touches the heap.
+/// Handy for short lists that get built every frame.
+pub struct FixedVec<T, const N: usize> {
+    items: [Option<T>; N],
+    len: usize,
+}
+
+impl<T, const N: usize> FixedVec<T, N> {
+    pub fn new() -> FixedVec<T, N> {
+        FixedVec {
+            items: core::array::from_fn(|_| None),
+            len: 0,
+        }
+    }
+
+    /// Add an item to the end, or hand it back if the list is already full.
+    pub fn push(&mut self, item: T) -> Result<(), T> {
+        if self.len == N {
+            return Err(item);
+        }
+        self.items[self.len] = Some(item);
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Remove and return the last item.
+    pub fn pop(&mut self) -> Option<T> {
+        if self.len == 0 {
+            return None;
+        }
+        self.len -= 1;
+        self.items[self.len].take()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len == N
+    }
+
+    pub fn clear(&mut self) {
+        while self.pop().is_some() {}
+    }
+
+    /// The items, first pushed first.
+    pub fn iter(&self) -> impl Iterator<Item = &T> + '_ {
+        self.items[..self.len].iter().filter_map(|item| item.as_ref())
+    }
+
+    pub fn contains(&self, item: &T) -> bool
+    where
+        T: PartialEq,
+    {
+        self.iter().any(|i| i == item)
+    }
+}
+
+impl<T, const N: usize> Default for FixedVec<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ring_buffer_overwrites_the_oldest_and_iterates_newest_first() {
+        let mut ring: RingBuffer<u32, 3> = RingBuffer::new();
+        assert!(ring.is_empty());
+        ring.push(1);
+        ring.push(2);
+        assert_eq!(ring.iter().copied().collect::<Vec<_>>(), [2, 1]);
+        for i in 3..=5 {
+            ring.push(i);
+        }
+        assert_eq!(ring.len(), 3);
+        assert_eq!(ring.iter().copied().collect::<Vec<_>>(), [5, 4, 3]);
+    }
+
+    #[test]
+    fn fixed_vec_hands_back_items_past_capacity() {
+        let mut list: FixedVec<u32, 3> = FixedVec::new();
+        for i in 1..=3 {
+            list.push(i).unwrap();
+        }
+        assert!(list.is_full());
+        assert_eq!(list.push(4), Err(4));
+        assert_eq!(list.iter().copied().collect::<Vec<_>>(), [1, 2, 3]);
+        assert!(list.contains(&2) && !list.contains(&4));
+        assert_eq!(list.pop(), Some(3));
+        list.push(5).unwrap();
+        assert_eq!(list.iter().copied().collect::<Vec<_>>(), [1, 2, 5]);
+        list.clear();
+        assert!(list.is_empty() && list.iter().next().is_none());
+    }
+}
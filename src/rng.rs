@@ -21,10 +21,12 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 // SOFTWARE.
 
+/// A PCG generator (pcg64_fast: a 128-bit multiplicative congruential generator with the XSL-RR output function), not a
+/// plain LCG. Only the high bits of the state are used, and they're mixed by the xor-shift and random rotation, so the low
+/// bits of `next()` are as good as the high ones and `next() % n` is fine for small `n`.
 pub struct Rng(u128);
 
 
-
 impl Rng {
     pub fn new() -> Self {
         Self(0x7369787465656E2062797465206E756Du128 | 1)
@@ -36,4 +38,49 @@ impl Rng {
         let xsl = ((self.0 >> 64) as u64) ^ (self.0 as u64);
         xsl.rotate_right(rot)     
     }
+
+    /// The whole generator state. A generator made with `from_state` carries on with exactly the same numbers.
+    pub fn state(&self) -> u128 {
+        self.0
+    }
+
+    /// The state of a multiplicative generator has to be odd (an even one decays to 0), so the low bit is forced on.
+    /// States from `state()` are always odd already, so they round-trip unchanged.
+    pub fn from_state(state: u128) -> Self {
+        Self(state | 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Chi-square of `samples` draws from `bucket` over `n` buckets, which should all be equally likely.
+    fn chi_square(rng: &mut Rng, n: usize, samples: usize, bucket: impl Fn(u64) -> usize) -> f64 {
+        let mut counts = vec![0usize; n];
+        for _ in 0..samples {
+            counts[bucket(rng.next())] += 1;
+        }
+        let expected = samples as f64 / n as f64;
+        counts.iter().map(|&c| (c as f64 - expected).powi(2) / expected).sum()
+    }
+
+    #[test]
+    fn low_and_high_bits_are_evenly_spread() {
+        // 15 degrees of freedom: the chi-square is above 37.7 with probability 0.001.
+        let mut rng = Rng::new();
+        assert!(chi_square(&mut rng, 16, 16000, |x| (x % 16) as usize) < 37.7);
+        assert!(chi_square(&mut rng, 16, 16000, |x| (x >> 60) as usize) < 37.7);
+    }
+
+    #[test]
+    fn from_state_carries_on_and_fixes_up_even_states() {
+        let mut rng = Rng::new();
+        rng.next();
+        let mut copy = Rng::from_state(rng.state());
+        assert!((0..10).all(|_| rng.next() == copy.next()));
+        let mut even = Rng::from_state(0);
+        assert!(even.state() % 2 == 1);
+        assert!((0..10).any(|_| even.next() != 0));
+    }
 }
\ No newline at end of file
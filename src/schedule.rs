@@ -0,0 +1,158 @@
+// Helpers for deciding when systems run.
+
+/// Run `system` only on every `every`-th frame (frames 0, every, 2 * every, ...), returning whether it ran.
+/// Handy for expensive systems that don't need to run every frame. An `every` of 0 is treated like 1 (every frame).
+pub fn throttle<F: FnOnce()>(frame: u32, every: u32, system: F) -> bool {
+    if frame.is_multiple_of(every.max(1)) {
+        system();
+        true
+    } else {
+        false
+    }
+}
+
+/// Spreads an expensive pass over a list (e.g. `entities`) across frames: each frame, `next_batch` hands out the next
+/// `batch_size` positions, carrying on from where the last frame stopped and starting over once it reaches the end.
+/// A full cycle covers every item once as long as the list doesn't change; if it shrinks, the cursor is pulled back in
+/// range, and items shifting around in the meantime can be skipped or repeated once.
+pub struct BatchProcessor {
+    batch_size: usize,
+    cursor: usize,
+}
+
+impl BatchProcessor {
+    /// A `batch_size` of 0 is treated like 1.
+    pub fn new(batch_size: usize) -> BatchProcessor {
+        BatchProcessor { batch_size: batch_size.max(1), cursor: 0 }
+    }
+
+    /// The positions to process this frame in a list of `len` items. A batch never wraps around, so the last batch of a
+    /// cycle can be short (and an item is never visited twice in one frame).
+    pub fn next_batch(&mut self, len: usize) -> core::ops::Range<usize> {
+        if self.cursor >= len {
+            self.cursor = 0;
+        }
+        let start = self.cursor;
+        let end = (start + self.batch_size).min(len);
+        self.cursor = end;
+        start..end
+    }
+
+    /// Whether the last batch reached the end of the list (so the next one starts a new cycle).
+    pub fn cycle_done(&self, len: usize) -> bool {
+        self.cursor >= len
+    }
+}
+
+struct ScheduledSystem<W> {
+    name: &'static str,
+    system: fn(&mut W),
+    enabled: bool,
+}
+
+/// Systems that run in the order they were added. Each one can be switched off (and back on) by name at runtime,
+/// e.g. to freeze physics for debugging while input and rendering keep running.
+pub struct Schedule<W> {
+    systems: Vec<ScheduledSystem<W>>,
+}
+
+impl<W> Schedule<W> {
+    /// `capacity` is how many systems will be added; the memory is reserved upfront.
+    pub fn new(capacity: usize) -> Schedule<W> {
+        Schedule {
+            systems: Vec::with_capacity(capacity),
+        }
+    }
+
+    /// Add a system to the end of the schedule. Systems start out enabled.
+    pub fn add(&mut self, name: &'static str, system: fn(&mut W)) {
+        self.systems.push(ScheduledSystem { name, system, enabled: true });
+    }
+
+    /// Switch a system on or off. Returns false if there's no system with that name.
+    pub fn set_enabled(&mut self, name: &str, enabled: bool) -> bool {
+        match self.systems.iter_mut().find(|s| s.name == name) {
+            Some(s) => {
+                s.enabled = enabled;
+                true
+            },
+            None => false,
+        }
+    }
+
+    pub fn is_enabled(&self, name: &str) -> bool {
+        self.systems.iter().any(|s| s.name == name && s.enabled)
+    }
+
+    /// Run every enabled system, in order. Disabled systems are skipped entirely.
+    pub fn run(&self, world: &mut W) {
+        for s in &self.systems {
+            if s.enabled {
+                (s.system)(world);
+            }
+        }
+    }
+}
+
+// An empty schedule (doesn't allocate), so a schedule stored inside the world can be taken out with `core::mem::take` while it runs.
+impl<W> Default for Schedule<W> {
+    fn default() -> Self {
+        Schedule { systems: Vec::new() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn throttle_runs_exactly_every_nth_frame() {
+        let mut ran_on = vec![];
+        for frame in 0..10 {
+            let ran = throttle(frame, 3, || ran_on.push(frame));
+            assert_eq!(ran, frame % 3 == 0);
+        }
+        assert_eq!(ran_on, [0, 3, 6, 9]);
+        assert!((0..5).all(|frame| throttle(frame, 0, || {})));
+    }
+
+    #[test]
+    fn disabled_systems_are_skipped() {
+        let mut schedule: Schedule<Vec<&str>> = Schedule::new(2);
+        schedule.add("a", |log| log.push("a"));
+        schedule.add("b", |log| log.push("b"));
+        assert!(schedule.set_enabled("a", false));
+        assert!(!schedule.set_enabled("missing", false));
+        let mut log = vec![];
+        schedule.run(&mut log);
+        assert_eq!(log, ["b"]);
+        assert!(!schedule.is_enabled("a") && schedule.is_enabled("b"));
+        schedule.set_enabled("a", true);
+        schedule.run(&mut log);
+        assert_eq!(log, ["b", "a", "b"]);
+    }
+
+    #[test]
+    fn batches_cover_every_item_once_per_cycle() {
+        let mut batches = BatchProcessor::new(3);
+        for _ in 0..2 {
+            let mut visits = [0; 10];
+            let mut frames = 0;
+            loop {
+                for i in batches.next_batch(visits.len()) {
+                    visits[i] += 1;
+                }
+                frames += 1;
+                if batches.cycle_done(visits.len()) {
+                    break;
+                }
+            }
+            assert_eq!(frames, 4);
+            assert_eq!(visits, [1; 10]);
+        }
+        // The list shrinking under the cursor restarts the cycle instead of going out of range.
+        batches.next_batch(10);
+        assert_eq!(batches.next_batch(2), 0..2);
+        assert_eq!(BatchProcessor::new(0).next_batch(5), 0..1);
+    }
+}
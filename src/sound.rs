@@ -0,0 +1,135 @@
+// Named sounds built on top of the raw `tone` import. Each helper packs the frequency/duration/volume/flags
+// arguments for a specific channel, see https://wasm4.org/docs/guides/audio for the packing format.
+
+use crate::wasm4::*;
+
+/// The packed arguments of one `tone` call. Build one with a helper below, then `play` it.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Tone {
+    pub frequency: u32,
+    pub duration: u32,
+    pub volume: u32,
+    pub flags: u32,
+}
+
+impl Tone {
+    pub fn play(&self) {
+        tone(self.frequency, self.duration, self.volume, self.flags);
+    }
+}
+
+/// Plays tones with simple ducking: the more tones a channel gets in one frame, the quieter each one is,
+/// so a burst of sounds (e.g. lots of balls linking at once) doesn't clip. Call `end_frame` once every frame.
+pub struct AudioMixer {
+    // How many tones each channel (pulse 1, pulse 2, triangle, noise) has played this frame.
+    counts: [u8; 4],
+}
+
+impl AudioMixer {
+    pub fn new() -> AudioMixer {
+        AudioMixer { counts: [0; 4] }
+    }
+
+    /// How loud the next tone on `channel` would be this frame, as a fraction of its own volume (1, 1/2, 1/3, ...).
+    pub fn volume_scale(&self, channel: usize) -> f32 {
+        1.0 / (self.counts[channel] as f32 + 1.0)
+    }
+
+    /// Play `tone` on its channel, turned down by however many tones that channel already played this frame.
+    pub fn play(&mut self, tone: Tone) {
+        let channel = (tone.flags & 0b11) as usize;
+        let scale = self.volume_scale(channel);
+        self.counts[channel] = self.counts[channel].saturating_add(1);
+        let sustain = (tone.volume & 0xff) as f32 * scale;
+        let peak = (tone.volume >> 8 & 0xff) as f32 * scale;
+        Tone { volume: pack_volume(sustain as u8, peak as u8), ..tone }.play();
+    }
+
+    pub fn end_frame(&mut self) {
+        self.counts = [0; 4];
+    }
+}
+
+impl Default for AudioMixer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Pack a start and end frequency (in Hz) into `tone`'s frequency argument. The channel slides from one to the other.
+pub fn pack_frequency(from: u16, to: u16) -> u32 {
+    from as u32 | (to as u32) << 16
+}
+
+/// Pack an ADSR envelope (each in frames) into `tone`'s duration argument.
+pub fn pack_duration(attack: u8, decay: u8, sustain: u8, release: u8) -> u32 {
+    (attack as u32) << 24 | (decay as u32) << 16 | sustain as u32 | (release as u32) << 8
+}
+
+/// Pack the sustain and peak volumes (0-100) into `tone`'s volume argument.
+pub fn pack_volume(sustain: u8, peak: u8) -> u32 {
+    sustain as u32 | (peak as u32) << 8
+}
+
+/// Short square-wave blip on the first pulse channel (50% duty cycle).
+pub fn beep() -> Tone {
+    Tone {
+        frequency: pack_frequency(880, 0),
+        duration: pack_duration(0, 0, 4, 2),
+        volume: pack_volume(30, 30),
+        flags: TONE_PULSE1 | TONE_MODE3,
+    }
+}
+
+/// Low percussive hit on the noise channel. The noise channel has no pitch or duty cycle to speak of,
+/// so the frequency only sets the noise "color" and no mode bits are set.
+pub fn thud() -> Tone {
+    Tone {
+        frequency: pack_frequency(120, 0),
+        duration: pack_duration(0, 8, 0, 4),
+        volume: pack_volume(0, 60),
+        flags: TONE_NOISE,
+    }
+}
+
+/// Smooth slide from one frequency to another over `frames`, on the triangle channel.
+/// The triangle channel has a fixed waveform, so no duty cycle mode is set.
+pub fn sweep(from: u16, to: u16, frames: u8) -> Tone {
+    Tone {
+        frequency: pack_frequency(from, to),
+        duration: pack_duration(0, 0, frames, 0),
+        volume: pack_volume(40, 40),
+        flags: TONE_TRIANGLE,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn helpers_pack_their_tone_arguments() {
+        assert_eq!(beep(), Tone { frequency: 880, duration: 2 << 8 | 4, volume: 30 << 8 | 30, flags: 0b1000 });
+        assert_eq!(thud(), Tone { frequency: 120, duration: 8 << 16 | 4 << 8, volume: 60 << 8, flags: 0b11 });
+        assert_eq!(sweep(220, 440, 30), Tone { frequency: 440 << 16 | 220, duration: 30, volume: 40 << 8 | 40, flags: 0b10 });
+    }
+
+    #[test]
+    fn pack_duration_puts_each_stage_in_its_byte() {
+        assert_eq!(pack_duration(1, 2, 3, 4), 0x01_02_04_03);
+    }
+
+    #[test]
+    fn mixer_turns_a_channel_down_as_more_tones_play_on_it() {
+        let mut mixer = AudioMixer::new();
+        let channel = (beep().flags & 0b11) as usize;
+        for n in 1..=4 {
+            assert_eq!(mixer.volume_scale(channel), 1.0 / n as f32);
+            mixer.play(beep());
+        }
+        // Other channels aren't affected, and the next frame starts over.
+        assert_eq!(mixer.volume_scale((thud().flags & 0b11) as usize), 1.0);
+        mixer.end_frame();
+        assert_eq!(mixer.volume_scale(channel), 1.0);
+    }
+}
@@ -0,0 +1,408 @@
+// Spatial queries over any component that has a position.
+
+use core::cell::Cell;
+
+use crate::ecs::{Entity, EntityMap, GenerationalIndexAllocator, GenerationalIndexError};
+use crate::geom::Aabb;
+
+/// Implement this on a component (e.g. kinematics) to use it with the spatial queries in this module.
+pub trait Position {
+    fn position(&self) -> (f32, f32);
+}
+
+/// Find the live entity closest to `(x, y)` that has a value in `array`, along with its distance.
+/// Without a grid, this checks every entity. With one (built from the same `entities`), it only checks the entities in
+/// the cells around the point, widening the search until it's sure nothing outside could be closer.
+/// On a tie, the entity that comes first in `entities` wins either way, so the result is deterministic.
+pub fn nearest<T: Position>(entities: &[Entity], array: &EntityMap<T>, allocator: &GenerationalIndexAllocator, grid: Option<&SpatialHash>, x: f32, y: f32) -> Option<(Entity, f32)> {
+    let Some(grid) = grid else {
+        return closest(entities, array, allocator, 0..entities.len(), x, y);
+    };
+    let width = grid.cols as f32 * grid.cell_size;
+    let height = grid.rows as f32 * grid.cell_size;
+    let mut radius = grid.cell_size;
+    loop {
+        let found = closest(entities, array, allocator, grid.candidates(x, y, radius), x, y);
+        // Anything outside the searched square is further away than `radius`, so a hit within it is the closest.
+        // Once the square covers the whole grid, every entity has been checked. (Written so that a NaN position
+        // counts as covering it too, so the search always ends.)
+        let covers_grid = !(x - radius > 0.0 || y - radius > 0.0 || x + radius < width || y + radius < height);
+        match found {
+            Some((_, dist)) if dist <= radius => return found,
+            _ if covers_grid => return found,
+            _ => radius *= 2.0,
+        }
+    }
+}
+
+// The closest entity to `(x, y)` out of the ones at `positions` in `entities`. Ties go to the one that comes first in `entities`.
+fn closest<T: Position>(entities: &[Entity], array: &EntityMap<T>, allocator: &GenerationalIndexAllocator, positions: impl Iterator<Item = usize>, x: f32, y: f32) -> Option<(Entity, f32)> {
+    let mut best: Option<(usize, f32)> = None;
+    for i in positions {
+        let Some(item) = entities.get(i).and_then(|e| array.get(e, allocator).ok()) else {
+            continue
+        };
+        let (px, py) = item.position();
+        let dist_sq = (px - x).powi(2) + (py - y).powi(2);
+        match best {
+            Some((best_i, best_dist_sq)) if best_dist_sq < dist_sq || (best_dist_sq == dist_sq && best_i < i) => {},
+            _ => best = Some((i, dist_sq)),
+        }
+    }
+    best.map(|(i, dist_sq)| (entities[i], dist_sq.sqrt()))
+}
+
+/// Bounding box `(min_x, min_y, max_x, max_y)` of the positions of every live entity that has a value in `array`
+/// (e.g. to frame all of them with a camera). `None` if there are no such entities.
+pub fn bounds<T: Position>(entities: &[Entity], array: &EntityMap<T>, allocator: &GenerationalIndexAllocator) -> Option<(f32, f32, f32, f32)> {
+    entities.iter()
+        .filter_map(|e| array.get(e, allocator).ok())
+        .map(|item| item.position())
+        .fold(None, |acc, (x, y)| match acc {
+            None => Some((x, y, x, y)),
+            Some((min_x, min_y, max_x, max_y)) => Some((min_x.min(x), min_y.min(y), max_x.max(x), max_y.max(y))),
+        })
+}
+
+/// Every live entity in `entities` that has a value in `array` positioned inside `region`.
+pub fn entities_in_region<'a, T: Position>(entities: &'a [Entity], array: &'a EntityMap<T>, allocator: &'a GenerationalIndexAllocator, region: Aabb) -> impl Iterator<Item = Entity> + 'a {
+    entities.iter().filter_map(move |e| {
+        let (x, y) = array.get(e, allocator).ok()?.position();
+        if region.contains(x, y) {
+            Some(*e)
+        } else {
+            None
+        }
+    })
+}
+
+/// Uniform grid over the play area, rebuilt from the entity positions whenever they move (a "broadphase").
+/// Instead of checking every pair of entities, only entities in nearby cells need to be checked.
+/// All memory is reserved upfront in `new`, so rebuilding every frame doesn't touch the heap.
+/// Positions outside the grid are clamped into the edge cells.
+pub struct SpatialHash {
+    cell_size: f32,
+    cols: usize,
+    rows: usize,
+    // Entities in cell `c` are `sorted[cell_start[c]..cell_start[c + 1]]` (cells are stored row by row).
+    cell_start: Vec<u16>,
+    // Positions in the `entities` slice the grid was built from, grouped by cell.
+    sorted: Vec<u16>,
+    // Distance checks done by `pairs_in_band` since the last `take_checks` (a `Cell`, since queries only borrow the grid).
+    checks: Cell<u32>,
+}
+
+impl SpatialHash {
+    /// `capacity` is the most entities the grid will ever be built from (e.g. the allocator's capacity).
+    pub fn new(width: f32, height: f32, cell_size: f32, capacity: usize) -> SpatialHash {
+        let cols = ((width / cell_size).ceil() as usize).max(1);
+        let rows = ((height / cell_size).ceil() as usize).max(1);
+        SpatialHash {
+            cell_size,
+            cols,
+            rows,
+            cell_start: vec![0; cols * rows + 1],
+            sorted: Vec::with_capacity(capacity),
+            checks: Cell::new(0),
+        }
+    }
+
+    fn cell_coords(&self, x: f32, y: f32) -> (usize, usize) {
+        let cx = ((x / self.cell_size) as isize).clamp(0, self.cols as isize - 1);
+        let cy = ((y / self.cell_size) as isize).clamp(0, self.rows as isize - 1);
+        (cx as usize, cy as usize)
+    }
+
+    /// Which cell a position falls in.
+    pub fn cell_of(&self, x: f32, y: f32) -> usize {
+        let (cx, cy) = self.cell_coords(x, y);
+        cy * self.cols + cx
+    }
+
+    /// The area covered by a cell, as `(x, y, width, height)`.
+    pub fn cell_bounds(&self, cell: usize) -> (f32, f32, f32, f32) {
+        let cx = (cell % self.cols) as f32;
+        let cy = (cell / self.cols) as f32;
+        (cx * self.cell_size, cy * self.cell_size, self.cell_size, self.cell_size)
+    }
+
+    /// How many pairwise distance checks `pairs_in_band` has done with this grid since the last call, resetting the count.
+    /// With a well-sized grid this grows roughly linearly with the number of entities, instead of quadratically.
+    pub fn take_checks(&self) -> u32 {
+        self.checks.replace(0)
+    }
+
+    /// Draw the cell boundaries, and how many entities are in each non-empty cell, on top of the screen.
+    /// Handy for picking a cell size. Only compiled with the `debug-draw` feature.
+    #[cfg(feature = "debug-draw")]
+    pub fn debug_draw(&self) {
+        use crate::wasm4::{hline, vline, DRAW_COLORS};
+        let width = self.cols as f32 * self.cell_size;
+        let height = self.rows as f32 * self.cell_size;
+        unsafe { *DRAW_COLORS = 0x0003 }
+        for col in 1..self.cols {
+            let (x, _, _, _) = self.cell_bounds(col);
+            vline(x as i32, 0, height as u32);
+        }
+        for row in 1..self.rows {
+            let (_, y, _, _) = self.cell_bounds(row * self.cols);
+            hline(0, y as i32, width as u32);
+        }
+        unsafe { *DRAW_COLORS = 0x0004 }
+        for cell in 0..self.cols * self.rows {
+            let count = self.cell_start[cell + 1] - self.cell_start[cell];
+            if count > 0 {
+                let (x, y, _, _) = self.cell_bounds(cell);
+                crate::hud::text_number(count as i32, x as i32 + 1, y as i32 + 1);
+            }
+        }
+    }
+
+    /// Rebuild the grid from the current positions of every entity in `entities` that has a value in `array`.
+    pub fn build<T: Position>(&mut self, entities: &[Entity], array: &EntityMap<T>, allocator: &GenerationalIndexAllocator) {
+        // Counting sort: count the entities in each cell, turn the counts into running totals (the end of each cell),
+        // then walk backwards through the entities, placing each one just before its cell's end.
+        for count in self.cell_start.iter_mut() {
+            *count = 0;
+        }
+        for e in entities {
+            if let Ok(item) = array.get(e, allocator) {
+                let (x, y) = item.position();
+                let c = self.cell_of(x, y);
+                self.cell_start[c] += 1;
+            }
+        }
+        for c in 1..self.cell_start.len() {
+            self.cell_start[c] += self.cell_start[c - 1];
+        }
+        self.sorted.clear();
+        self.sorted.resize(self.cell_start[self.cell_start.len() - 1] as usize, 0);
+        for (i, e) in entities.iter().enumerate().rev() {
+            if let Ok(item) = array.get(e, allocator) {
+                let (x, y) = item.position();
+                let c = self.cell_of(x, y);
+                self.cell_start[c] -= 1;
+                self.sorted[self.cell_start[c] as usize] = i as u16;
+            }
+        }
+    }
+
+    /// The entities the grid was built from (pass the same `entities` slice again), grouped cell by cell. The cells are visited
+    /// in Z-order (Morton order), so entities that are close on screen also end up close together in the list, which helps
+    /// systems that look at neighbours stay cache-friendly.
+    pub fn entities_in_cell_order(&self, entities: &[Entity]) -> Vec<Entity> {
+        let mut ordered = Vec::with_capacity(self.sorted.len());
+        // Walk the Z-curve over the smallest power-of-two square covering the grid, skipping cells past its edges.
+        let side = self.cols.max(self.rows).next_power_of_two();
+        for code in 0..side * side {
+            let cx = compact_bits(code);
+            let cy = compact_bits(code >> 1);
+            if cx < self.cols && cy < self.rows {
+                let cell = cy * self.cols + cx;
+                let cell_entities = &self.sorted[self.cell_start[cell] as usize..self.cell_start[cell + 1] as usize];
+                ordered.extend(cell_entities.iter().map(|&i| entities[i as usize]));
+            }
+        }
+        ordered
+    }
+
+    /// Positions (in the `entities` slice the grid was built from) of every entity in a cell touching the square of
+    /// `radius` around `(x, y)`. These are only candidates: the caller still needs to check the actual distance.
+    pub fn candidates(&self, x: f32, y: f32, radius: f32) -> impl Iterator<Item = usize> + '_ {
+        let (x0, y0) = self.cell_coords(x - radius, y - radius);
+        let (x1, y1) = self.cell_coords(x + radius, y + radius);
+        // Cells in a row are stored next to each other, so each row of the square is one slice.
+        (y0..=y1).flat_map(move |cy| {
+            let start = self.cell_start[cy * self.cols + x0] as usize;
+            let end = self.cell_start[cy * self.cols + x1 + 1] as usize;
+            self.sorted[start..end].iter().map(|&i| i as usize)
+        })
+    }
+}
+
+/// `entity`'s value (mutably), along with every other entity within `radius` of it and their values (read-only),
+/// using the grid (built from the same `entities`) to skip far-away entities. Handy for local interactions like flocking.
+pub fn get_mut_with_neighbors<'a, T: Position>(array: &'a mut EntityMap<T>, grid: &'a SpatialHash, entities: &'a [Entity], allocator: &'a GenerationalIndexAllocator, entity: &Entity, radius: f32) -> Result<(&'a mut T, impl Iterator<Item = (Entity, &'a T)> + 'a), GenerationalIndexError> {
+    let (item, others) = array.get_mut_and_others(entity, allocator)?;
+    let (x, y) = item.position();
+    let neighbors = grid.candidates(x, y, radius).filter_map(move |j| {
+        let e = *entities.get(j)?;
+        let other = others.get(&e, allocator)?;
+        let (ox, oy) = other.position();
+        if (ox - x).powi(2) + (oy - y).powi(2) < radius.powi(2) {
+            Some((e, other))
+        } else {
+            None
+        }
+    });
+    Ok((item, neighbors))
+}
+
+// Keep the even bits of `code` (0, 2, 4, ...) packed together, which undoes the bit interleaving of a Morton code.
+fn compact_bits(code: usize) -> usize {
+    let mut out = 0;
+    for bit in 0..usize::BITS / 2 {
+        out |= ((code >> (2 * bit)) & 1) << bit;
+    }
+    out
+}
+
+/// Every pair of entities whose distance is in `[min, max)`, using the grid (built from the same `entities`) to skip far-away pairs.
+/// Each pair is yielded once, with the entity that comes first in `entities` first.
+pub fn pairs_in_band<'a, T: Position>(grid: &'a SpatialHash, entities: &'a [Entity], array: &'a EntityMap<T>, allocator: &'a GenerationalIndexAllocator, min: f32, max: f32) -> impl Iterator<Item = (Entity, Entity)> + 'a {
+    entities.iter().enumerate()
+        .filter_map(move |(i, e1)| array.get(e1, allocator).ok().map(|item| (i, *e1, item.position())))
+        .flat_map(move |(i, e1, (x1, y1))| {
+            grid.candidates(x1, y1, max).filter(move |&j| j > i).filter_map(move |j| {
+                let e2 = entities.get(j)?;
+                let (x2, y2) = array.get(e2, allocator).ok()?.position();
+                grid.checks.set(grid.checks.get().saturating_add(1));
+                let dist_sq = (x1 - x2).powi(2) + (y1 - y2).powi(2);
+                if dist_sq >= min.powi(2) && dist_sq < max.powi(2) {
+                    Some((e1, *e2))
+                } else {
+                    None
+                }
+            })
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ecs::{AllocatorEntry, GenerationalIndexArray, IndexType};
+
+    struct Point(f32, f32);
+
+    impl Position for Point {
+        fn position(&self) -> (f32, f32) {
+            (self.0, self.1)
+        }
+    }
+
+    // A live entity at each of `points`, with the allocator they're live in.
+    fn entities_at(points: &[(f32, f32)]) -> (GenerationalIndexAllocator, Vec<Entity>, EntityMap<Point>) {
+        let n = points.len();
+        let mut allocator = GenerationalIndexAllocator::new((0..n).map(|_| AllocatorEntry::new()).collect(), (0..n).map(|i| i as IndexType).collect());
+        let entities: Vec<Entity> = points.iter().filter_map(|_| allocator.allocate().ok()).collect();
+        let mut array = GenerationalIndexArray((0..n).map(|_| Point(0.0, 0.0)).collect());
+        for (e, &(x, y)) in entities.iter().zip(points) {
+            array.set(e, &allocator, Point(x, y)).unwrap();
+        }
+        (allocator, entities, array)
+    }
+
+    #[test]
+    fn nearest_finds_the_closest_entity_with_or_without_a_grid() {
+        let (allocator, entities, array) = entities_at(&[(10.0, 10.0), (100.0, 100.0), (150.0, 20.0), (60.0, 60.0)]);
+        let mut grid = SpatialHash::new(160.0, 160.0, 16.0, entities.len());
+        grid.build(&entities, &array, &allocator);
+        for grid in [None, Some(&grid)] {
+            let (e, dist) = nearest(&entities, &array, &allocator, grid, 95.0, 88.0).unwrap();
+            assert!(e == entities[1]);
+            assert_eq!(dist, 13.0);
+            // Far from everything, so the grid search has to widen a few times.
+            let (e, _) = nearest(&entities, &array, &allocator, grid, 0.0, 159.0).unwrap();
+            assert!(e == entities[3]);
+        }
+    }
+
+    #[test]
+    fn nearest_breaks_ties_by_entity_order_and_handles_no_entities() {
+        let (allocator, entities, array) = entities_at(&[(100.0, 50.0), (0.0, 50.0), (50.0, 100.0)]);
+        let mut grid = SpatialHash::new(160.0, 160.0, 16.0, entities.len());
+        grid.build(&entities, &array, &allocator);
+        for grid in [None, Some(&grid)] {
+            let (e, dist) = nearest(&entities, &array, &allocator, grid, 50.0, 50.0).unwrap();
+            assert!(e == entities[0]);
+            assert_eq!(dist, 50.0);
+            assert!(nearest(&[], &array, &allocator, grid, 50.0, 50.0).is_none());
+        }
+    }
+
+    #[test]
+    fn pairs_in_band_only_yields_pairs_in_range() {
+        let (allocator, entities, array) = entities_at(&[(50.0, 50.0), (53.0, 50.0), (50.0, 55.0), (62.0, 50.0)]);
+        let mut grid = SpatialHash::new(160.0, 160.0, 16.0, entities.len());
+        grid.build(&entities, &array, &allocator);
+        let pairs: Vec<_> = pairs_in_band(&grid, &entities, &array, &allocator, 4.0, 10.0).collect();
+        let index = |e| entities.iter().position(|x| *x == e).unwrap();
+        let pairs: Vec<_> = pairs.into_iter().map(|(a, b)| (index(a), index(b))).collect();
+        // 0-2 is 5 apart, 1-2 about 5.8 and 1-3 is 9. 0-1 (3) is too close, 0-3 (12) and 2-3 (13) too far.
+        assert_eq!(pairs, [(0, 2), (1, 2), (1, 3)]);
+    }
+
+    #[test]
+    fn cell_bounds_match_the_grid_layout() {
+        // 100 / 16 doesn't divide evenly, so there's a partial 7th column and row.
+        let grid = SpatialHash::new(100.0, 40.0, 16.0, 0);
+        assert_eq!((grid.cols, grid.rows), (7, 3));
+        assert_eq!(grid.cell_bounds(0), (0.0, 0.0, 16.0, 16.0));
+        assert_eq!(grid.cell_bounds(6), (96.0, 0.0, 16.0, 16.0));
+        assert_eq!(grid.cell_bounds(7), (0.0, 16.0, 16.0, 16.0));
+        assert_eq!(grid.cell_bounds(20), (96.0, 32.0, 16.0, 16.0));
+        assert_eq!(grid.cell_of(17.0, 33.0), 15);
+        // Outside the grid clamps into the edge cells.
+        assert_eq!(grid.cell_of(-5.0, 500.0), 14);
+    }
+
+    #[test]
+    fn cell_order_groups_entities_by_cell_in_z_order() {
+        // Cells (1, 0), (0, 0), (1, 0), (0, 1), (3, 3), (1, 1).
+        let points = [(20.0, 5.0), (5.0, 5.0), (30.0, 10.0), (5.0, 20.0), (60.0, 60.0), (20.0, 20.0)];
+        let (allocator, entities, array) = entities_at(&points);
+        let mut grid = SpatialHash::new(64.0, 64.0, 16.0, entities.len());
+        grid.build(&entities, &array, &allocator);
+        let ordered = grid.entities_in_cell_order(&entities);
+        let order: Vec<_> = ordered.iter().map(|e| entities.iter().position(|x| x == e).unwrap()).collect();
+        assert_eq!(order, [1, 0, 2, 3, 5, 4]);
+    }
+
+    #[test]
+    fn neighbors_exclude_the_entity_itself_and_far_ones() {
+        let (allocator, entities, mut array) = entities_at(&[(50.0, 50.0), (55.0, 50.0), (50.0, 58.0), (70.0, 50.0), (50.0, 50.0)]);
+        let mut grid = SpatialHash::new(160.0, 160.0, 16.0, entities.len());
+        grid.build(&entities, &array, &allocator);
+        let (item, neighbors) = get_mut_with_neighbors(&mut array, &grid, &entities, &allocator, &entities[0], 10.0).unwrap();
+        let mut found: Vec<_> = neighbors.map(|(e, _)| entities.iter().position(|x| *x == e).unwrap()).collect();
+        found.sort();
+        // The one at the very same spot is a neighbour too; only the entity itself is left out.
+        assert_eq!(found, [1, 2, 4]);
+        item.0 = 0.0;
+        assert_eq!(array.get(&entities[0], &allocator).unwrap().0, 0.0);
+    }
+
+    #[test]
+    fn bounds_covers_every_position_and_is_none_when_empty() {
+        let (allocator, entities, array) = entities_at(&[(30.0, 80.0), (5.0, 120.0), (140.0, 40.0)]);
+        assert_eq!(bounds(&entities, &array, &allocator), Some((5.0, 40.0, 140.0, 120.0)));
+        assert_eq!(bounds(&entities[..1], &array, &allocator), Some((30.0, 80.0, 30.0, 80.0)));
+        assert_eq!(bounds(&[], &array, &allocator), None);
+    }
+
+    #[test]
+    fn pair_checks_grow_linearly_with_the_entity_count() {
+        // Balls 10 apart on a `cols` x `rows` lattice, so the density stays the same as the count grows.
+        let checks = |cols: usize, rows: usize| {
+            let points: Vec<_> = (0..cols * rows).map(|i| ((i % cols) as f32 * 10.0 + 5.0, (i / cols) as f32 * 10.0 + 5.0)).collect();
+            let (allocator, entities, array) = entities_at(&points);
+            let mut grid = SpatialHash::new(cols as f32 * 10.0, rows as f32 * 10.0, 16.0, entities.len());
+            grid.build(&entities, &array, &allocator);
+            assert!(pairs_in_band(&grid, &entities, &array, &allocator, 0.0, 12.0).count() > 0);
+            grid.take_checks()
+        };
+        let (small, large) = (checks(10, 5), checks(20, 10));
+        // 4 times the balls: about 4 times the checks, where checking every pair would take 16 times as many.
+        assert!(large < small * 6, "{} checks at n=50, {} at n=200", small, large);
+        assert!(large < 200 * 199 / 2 / 4);
+    }
+
+    #[test]
+    fn entities_in_region_yields_only_the_ones_inside() {
+        let (mut allocator, entities, array) = entities_at(&[(10.0, 10.0), (79.0, 20.0), (80.0, 20.0), (40.0, 79.5), (5.0, 5.0)]);
+        allocator.deallocate(&entities[4]).unwrap();
+        let found: Vec<_> = entities_in_region(&entities, &array, &allocator, Aabb::new(0.0, 0.0, 80.0, 80.0)).collect();
+        // The right and bottom edges are outside, and dead entities are skipped.
+        assert!(found == [entities[0], entities[1], entities[3]]);
+    }
+}
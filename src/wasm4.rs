@@ -17,18 +17,31 @@ pub const SCREEN_SIZE: u32 = 160;
 // │                                                                           │
 // └───────────────────────────────────────────────────────────────────────────┘
 
-pub const PALETTE: *mut [u32; 4] = 0x04 as _;
-pub const DRAW_COLORS: *mut u16 = 0x14 as _;
-pub const GAMEPAD1: *const u8 = 0x16 as _;
-pub const GAMEPAD2: *const u8 = 0x17 as _;
-pub const GAMEPAD3: *const u8 = 0x18 as _;
-pub const GAMEPAD4: *const u8 = 0x19 as _;
-pub const MOUSE_X: *const i16 = 0x1a as _;
-pub const MOUSE_Y: *const i16 = 0x1c as _;
-pub const MOUSE_BUTTONS: *const u8 = 0x1e as _;
-pub const SYSTEM_FLAGS: *mut u8 = 0x1f as _;
-pub const NETPLAY: *const u8 = 0x20 as _;
-pub const FRAMEBUFFER: *mut [u8; 6400] = 0xa0 as _;
+pub const PALETTE: *mut [u32; 4] = addr(0x04);
+pub const DRAW_COLORS: *mut u16 = addr(0x14);
+pub const GAMEPAD1: *const u8 = addr::<u8>(0x16);
+pub const GAMEPAD2: *const u8 = addr::<u8>(0x17);
+pub const GAMEPAD3: *const u8 = addr::<u8>(0x18);
+pub const GAMEPAD4: *const u8 = addr::<u8>(0x19);
+// The gamepads by player index (player 0 is `GAMEPAD1`).
+pub const GAMEPADS: [*const u8; 4] = [GAMEPAD1, GAMEPAD2, GAMEPAD3, GAMEPAD4];
+pub const MOUSE_X: *const i16 = addr::<i16>(0x1a);
+pub const MOUSE_Y: *const i16 = addr::<i16>(0x1c);
+pub const MOUSE_BUTTONS: *const u8 = addr::<u8>(0x1e);
+pub const SYSTEM_FLAGS: *mut u8 = addr(0x1f);
+pub const NETPLAY: *const u8 = addr::<u8>(0x20);
+pub const FRAMEBUFFER: *mut [u8; 6400] = addr(0xa0);
+
+// Where a memory-mapped register lives. With the `headless` feature, that's inside `mock`'s stand-in memory instead.
+#[cfg(not(feature = "headless"))]
+const fn addr<T>(offset: usize) -> *mut T {
+    offset as *mut T
+}
+
+#[cfg(feature = "headless")]
+const fn addr<T>(offset: usize) -> *mut T {
+    mock::register(offset)
+}
 
 pub const BUTTON_1: u8 = 1;
 pub const BUTTON_2: u8 = 2;
@@ -54,6 +67,7 @@ pub const SYSTEM_HIDE_GAMEPAD_OVERLAY: u8 = 2;
 pub fn blit(sprite: &[u8], x: i32, y: i32, width: u32, height: u32, flags: u32) {
     unsafe { extern_blit(sprite.as_ptr(), x, y, width, height, flags) }
 }
+#[cfg(not(feature = "headless"))]
 extern "C" {
     #[link_name = "blit"]
     fn extern_blit(sprite: *const u8, x: i32, y: i32, width: u32, height: u32, flags: u32);
@@ -86,6 +100,7 @@ pub fn blit_sub(
         )
     }
 }
+#[cfg(not(feature = "headless"))]
 extern "C" {
     #[link_name = "blitSub"]
     fn extern_blit_sub(
@@ -111,6 +126,7 @@ pub const BLIT_ROTATE: u32 = 8;
 pub fn line(x1: i32, y1: i32, x2: i32, y2: i32) {
     unsafe { extern_line(x1, y1, x2, y2) }
 }
+#[cfg(not(feature = "headless"))]
 extern "C" {
     #[link_name = "line"]
     fn extern_line(x1: i32, y1: i32, x2: i32, y2: i32);
@@ -120,6 +136,7 @@ extern "C" {
 pub fn oval(x: i32, y: i32, width: u32, height: u32) {
     unsafe { extern_oval(x, y, width, height) }
 }
+#[cfg(not(feature = "headless"))]
 extern "C" {
     #[link_name = "oval"]
     fn extern_oval(x: i32, y: i32, width: u32, height: u32);
@@ -129,6 +146,7 @@ extern "C" {
 pub fn rect(x: i32, y: i32, width: u32, height: u32) {
     unsafe { extern_rect(x, y, width, height) }
 }
+#[cfg(not(feature = "headless"))]
 extern "C" {
     #[link_name = "rect"]
     fn extern_rect(x: i32, y: i32, width: u32, height: u32);
@@ -139,6 +157,7 @@ pub fn text<T: AsRef<[u8]>>(text: T, x: i32, y: i32) {
     let text_ref = text.as_ref();
     unsafe { extern_text(text_ref.as_ptr(), text_ref.len(), x, y) }
 }
+#[cfg(not(feature = "headless"))]
 extern "C" {
     #[link_name = "textUtf8"]
     fn extern_text(text: *const u8, length: usize, x: i32, y: i32);
@@ -151,6 +170,7 @@ pub fn vline(x: i32, y: i32, len: u32) {
     }
 }
 
+#[cfg(not(feature = "headless"))]
 extern "C" {
     #[link_name = "vline"]
     fn extern_vline(x: i32, y: i32, len: u32);
@@ -163,11 +183,41 @@ pub fn hline(x: i32, y: i32, len: u32) {
     }
 }
 
+#[cfg(not(feature = "headless"))]
 extern "C" {
     #[link_name = "hline"]
     fn extern_hline(x: i32, y: i32, len: u32);
 }
 
+/// Where pixel `(x, y)` lives in the framebuffer: the byte index and the bit shift within it, or `None` if it's off screen.
+/// Each byte packs 4 pixels at 2 bits each, with the leftmost pixel in the lowest bits.
+fn pixel_location(x: i32, y: i32) -> Option<(usize, u32)> {
+    if x < 0 || y < 0 || x >= SCREEN_SIZE as i32 || y >= SCREEN_SIZE as i32 {
+        return None;
+    }
+    let i = (y as usize) * SCREEN_SIZE as usize + x as usize;
+    Some((i / 4, (i % 4) as u32 * 2))
+}
+
+/// Reads the palette index (0 to 3) of one pixel of the framebuffer. Off-screen pixels read as 0.
+pub fn get_pixel(x: i32, y: i32) -> u8 {
+    match pixel_location(x, y) {
+        Some((byte, shift)) => unsafe { ((*FRAMEBUFFER)[byte] >> shift) & 0b11 },
+        None => 0,
+    }
+}
+
+/// Sets one pixel of the framebuffer to a palette index (0 to 3, higher bits are ignored). Off-screen pixels are ignored.
+/// Unlike the other drawing functions, this writes the palette index directly and doesn't go through `DRAW_COLORS`.
+pub fn set_pixel(x: i32, y: i32, color: u8) {
+    if let Some((byte, shift)) = pixel_location(x, y) {
+        unsafe {
+            let fb = &mut *FRAMEBUFFER;
+            fb[byte] = (fb[byte] & !(0b11 << shift)) | ((color & 0b11) << shift);
+        }
+    }
+}
+
 // ┌───────────────────────────────────────────────────────────────────────────┐
 // │                                                                           │
 // │ Sound Functions                                                           │
@@ -178,6 +228,7 @@ extern "C" {
 pub fn tone(frequency: u32, duration: u32, volume: u32, flags: u32) {
     unsafe { extern_tone(frequency, duration, volume, flags) }
 }
+#[cfg(not(feature = "headless"))]
 extern "C" {
     #[link_name = "tone"]
     fn extern_tone(frequency: u32, duration: u32, volume: u32, flags: u32);
@@ -200,6 +251,7 @@ pub const TONE_PAN_RIGHT: u32 = 32;
 // │                                                                           │
 // └───────────────────────────────────────────────────────────────────────────┘
 
+#[cfg(not(feature = "headless"))]
 extern "C" {
     /// Reads up to `size` bytes from persistent storage into the pointer `dest`.
     pub fn diskr(dest: *mut u8, size: u32) -> u32;
@@ -219,7 +271,174 @@ pub fn trace<T: AsRef<str>>(text: T) {
     let text_ref = text.as_ref();
     unsafe { extern_trace(text_ref.as_ptr(), text_ref.len()) }
 }
+#[cfg(not(feature = "headless"))]
 extern "C" {
     #[link_name = "traceUtf8"]
     fn extern_trace(trace: *const u8, length: usize);
 }
+
+// The bits of the `NETPLAY` byte: set while netplay is active, plus the local player's index (0-3) in the low bits.
+const NETPLAY_ACTIVE: u8 = 0b100;
+const NETPLAY_PLAYER_MASK: u8 = 0b011;
+
+/// The local player's index (0-3, i.e. which `GAMEPAD` is theirs) while netplay is active, or `None` for a local game.
+pub fn netplay_player() -> Option<u8> {
+    let netplay = unsafe { *NETPLAY };
+    if netplay & NETPLAY_ACTIVE != 0 {
+        Some(netplay & NETPLAY_PLAYER_MASK)
+    } else {
+        None
+    }
+}
+
+// ┌───────────────────────────────────────────────────────────────────────────┐
+// │                                                                           │
+// │ Headless Mock                                                             │
+// │                                                                           │
+// └───────────────────────────────────────────────────────────────────────────┘
+
+#[cfg(feature = "headless")]
+use mock::*;
+#[cfg(feature = "headless")]
+pub use mock::{diskr, diskw};
+
+/// Stand-ins for everything the WASM-4 runtime provides (enabled with the `headless` feature), so the game can run
+/// without it, e.g. driven frame by frame from host-side tests. Drawing and sound do nothing, `trace` goes to stderr,
+/// the disk is a plain buffer, and the memory-mapped registers live in a static array that `set_gamepad`/`set_mouse` fill in.
+#[cfg(feature = "headless")]
+pub mod mock {
+    // Everything up to the end of the framebuffer. Aligned so the `u32`/`u16` registers are too.
+    #[repr(C, align(4))]
+    struct Memory([u8; 0xa0 + 6400]);
+    static mut MEMORY: Memory = Memory([0; 0xa0 + 6400]);
+
+    static mut DISK: [u8; 1024] = [0; 1024];
+    static mut DISK_LEN: usize = 0;
+
+    pub(super) const fn register<T>(offset: usize) -> *mut T {
+        unsafe { (&raw mut MEMORY).cast::<u8>().add(offset).cast() }
+    }
+
+    /// Set what `GAMEPAD1` reads for the next frames.
+    pub fn set_gamepad(buttons: u8) {
+        set_player_gamepad(0, buttons);
+    }
+
+    /// Set what player `player`'s gamepad (0-3, i.e. `GAMEPAD1`-`GAMEPAD4`) reads for the next frames.
+    pub fn set_player_gamepad(player: usize, buttons: u8) {
+        unsafe { *register::<u8>(0x16 + player % 4) = buttons }
+    }
+
+    /// Set what `NETPLAY` reads: active with the given local player index (0-3), or inactive for `None`.
+    pub fn set_netplay(player: Option<u8>) {
+        unsafe {
+            *register::<u8>(0x20) = match player {
+                Some(index) => super::NETPLAY_ACTIVE | (index & super::NETPLAY_PLAYER_MASK),
+                None => 0,
+            }
+        }
+    }
+
+    /// Set what `MOUSE_X`, `MOUSE_Y` and `MOUSE_BUTTONS` read for the next frames.
+    pub fn set_mouse(x: i16, y: i16, buttons: u8) {
+        unsafe {
+            *register::<i16>(0x1a) = x;
+            *register::<i16>(0x1c) = y;
+            *register::<u8>(0x1e) = buttons;
+        }
+    }
+
+    /// Tests run in parallel, but they all share this memory (and the disk), so a test that touches it holds this until
+    /// it's done. Everything is zeroed again when it's taken, like at startup.
+    #[cfg(test)]
+    pub fn lock() -> std::sync::MutexGuard<'static, ()> {
+        static LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+        // A failed test poisons the lock, but the memory is reset anyway, so there's nothing to worry about.
+        let guard = LOCK.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        unsafe {
+            register::<Memory>(0).write_bytes(0, 1);
+            DISK_LEN = 0;
+        }
+        guard
+    }
+
+    pub(super) unsafe fn extern_blit(_sprite: *const u8, _x: i32, _y: i32, _width: u32, _height: u32, _flags: u32) {}
+    #[allow(clippy::too_many_arguments)]
+    pub(super) unsafe fn extern_blit_sub(_sprite: *const u8, _x: i32, _y: i32, _width: u32, _height: u32, _src_x: u32, _src_y: u32, _stride: u32, _flags: u32) {}
+    pub(super) unsafe fn extern_line(_x1: i32, _y1: i32, _x2: i32, _y2: i32) {}
+    pub(super) unsafe fn extern_oval(_x: i32, _y: i32, _width: u32, _height: u32) {}
+    pub(super) unsafe fn extern_rect(_x: i32, _y: i32, _width: u32, _height: u32) {}
+    pub(super) unsafe fn extern_text(_text: *const u8, _length: usize, _x: i32, _y: i32) {}
+    pub(super) unsafe fn extern_vline(_x: i32, _y: i32, _len: u32) {}
+    pub(super) unsafe fn extern_hline(_x: i32, _y: i32, _len: u32) {}
+    pub(super) unsafe fn extern_tone(_frequency: u32, _duration: u32, _volume: u32, _flags: u32) {}
+
+    pub(super) unsafe fn extern_trace(trace: *const u8, length: usize) {
+        let bytes = core::slice::from_raw_parts(trace, length);
+        eprintln!("{}", String::from_utf8_lossy(bytes));
+    }
+
+    /// Like the real `diskr`: reads up to `size` bytes of what was last written with `diskw`.
+    pub unsafe fn diskr(dest: *mut u8, size: u32) -> u32 {
+        let n = (size as usize).min(DISK_LEN);
+        core::ptr::copy_nonoverlapping((&raw const DISK).cast::<u8>(), dest, n);
+        n as u32
+    }
+
+    /// Like the real `diskw`: replaces the disk contents with up to 1024 bytes from `src`.
+    pub unsafe fn diskw(src: *const u8, size: u32) -> u32 {
+        let n = (size as usize).min(1024);
+        core::ptr::copy_nonoverlapping(src, (&raw mut DISK).cast::<u8>(), n);
+        DISK_LEN = n;
+        n as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pixel_location_packs_four_pixels_per_byte() {
+        assert_eq!(pixel_location(0, 0), Some((0, 0)));
+        assert_eq!(pixel_location(3, 0), Some((0, 6)));
+        assert_eq!(pixel_location(4, 0), Some((1, 0)));
+        assert_eq!(pixel_location(159, 159), Some((6399, 6)));
+        assert_eq!(pixel_location(160, 0), None);
+        assert_eq!(pixel_location(0, -1), None);
+    }
+
+    #[cfg(feature = "headless")]
+    #[test]
+    fn set_pixel_round_trips_without_touching_neighbours() {
+        let _lock = mock::lock();
+        for (x, y) in [(0, 0), (1, 0), (2, 7), (3, 80), (159, 159)] {
+            for color in 0..4 {
+                set_pixel(x, y, color);
+                assert_eq!(get_pixel(x, y), color);
+            }
+        }
+        // Pixels sharing a byte keep their own colors.
+        for x in 4..8 {
+            set_pixel(x, 1, x as u8 % 4);
+        }
+        assert_eq!((4..8).map(|x| get_pixel(x, 1)).collect::<Vec<_>>(), [0, 1, 2, 3]);
+        assert_eq!(unsafe { (*FRAMEBUFFER)[SCREEN_SIZE as usize / 4 + 1] }, 0b11_10_01_00);
+        // Off screen: nothing is written, and it reads as 0.
+        set_pixel(-1, 0, 3);
+        assert_eq!(get_pixel(-1, 0), 0);
+    }
+
+    #[cfg(feature = "headless")]
+    #[test]
+    fn netplay_player_is_the_local_index_only_while_active() {
+        let _lock = mock::lock();
+        assert_eq!(netplay_player(), None);
+        for player in 0..4 {
+            mock::set_netplay(Some(player));
+            assert_eq!(netplay_player(), Some(player));
+        }
+        mock::set_netplay(None);
+        assert_eq!(netplay_player(), None);
+    }
+}